@@ -0,0 +1,66 @@
+use crate::config::Build;
+use crate::target::Target;
+use crate::Result;
+use anyhow::{bail, Context};
+use console::style;
+use std::collections::HashSet;
+use std::io;
+use std::process::Command;
+
+///编译配置了 build 的可执行文件, 同一个可执行文件部署到多个主机时只编译一次
+pub(crate) fn build(targets: &[Target]) -> Result<()> {
+    let mut built = HashSet::new();
+    for target in targets {
+        let Ok(executable) = target.executable_config() else {
+            continue;
+        };
+        let Some(build) = &executable.build else {
+            continue;
+        };
+        if !built.insert((&target.config, &target.id)) {
+            continue;
+        }
+        let name = format!("{}/{}", target.config, target.id);
+        eprintln!("{} {}", style("build").cyan(), name);
+        //编译输出写到标准错误, 不影响 json 和 yaml 输出
+        let status = build_command(build)
+            .stdout(io::stderr())
+            .status()
+            .with_context(|| format!("编译 {} 失败", name))?;
+        if !status.success() {
+            bail!("编译 {} 失败: {}", name, status);
+        }
+    }
+    Ok(())
+}
+
+///--dry-run 时部署计划中的编译步骤, 没有配置 build 时为 None
+pub(crate) fn build_step(target: &Target) -> Option<String> {
+    let build = target.executable_config().ok()?.build.as_ref()?;
+    let command = build_command(build);
+    let mut step = format!("local: {}", command.get_program().to_string_lossy());
+    command
+        .get_args()
+        .for_each(|arg| step.push_str(&format!(" {}", arg.to_string_lossy())));
+    Some(step)
+}
+
+fn build_command(build: &Build) -> Command {
+    if let Some(command) = &build.command {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(command);
+        return cmd;
+    }
+    let mut cmd = Command::new("cargo");
+    cmd.arg("build").arg("--profile").arg(&build.profile);
+    if let Some(package) = &build.package {
+        cmd.arg("-p").arg(package);
+    }
+    if let Some(target) = &build.target {
+        cmd.arg("--target").arg(target);
+    }
+    if !build.features.is_empty() {
+        cmd.arg("--features").arg(build.features.join(","));
+    }
+    cmd
+}
@@ -1,5 +1,7 @@
+use crate::deploy::BatchSize;
+use crate::report::OutputFormat;
 use clap::{Args, Parser, Subcommand};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about = "upload app and executable operation", long_about = None)]
@@ -10,6 +12,25 @@ use std::path::Path;
 pub(crate) struct Cli {
     #[command(subcommand)]
     pub(crate) command: Commands,
+
+    /// Output format of the results
+    #[arg(long, short, global = true, value_enum, default_value_t = OutputFormat::Text)]
+    pub(crate) output: OutputFormat,
+
+    /// Profile in the config files to apply
+    #[arg(long, global = true)]
+    pub(crate) profile: Option<String>,
+
+    /// Set a config variable, e.g. --set version=1.2
+    #[arg(long = "set", global = true, value_name = "KEY=VALUE", value_parser = parse_var)]
+    pub(crate) vars: Vec<(String, String)>,
+}
+
+fn parse_var(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((k, v)) if !k.is_empty() => Ok((k.to_owned(), v.to_owned())),
+        _ => Err(format!("invalid KEY=VALUE: {}", s)),
+    }
 }
 
 #[derive(Subcommand, Debug)]
@@ -18,6 +39,23 @@ pub(crate) enum Commands {
     Run(Run),
     /// show the log of the application
     Log(Log),
+    /// show whether the application is running
+    Status(Status),
+    /// manage the config files
+    #[command(subcommand)]
+    Config(ConfigCommands),
+    /// create a config file interactively
+    Init(Init),
+    /// redeploy whenever the local executable changes and stream the log
+    Watch(Watch),
+}
+
+#[derive(Subcommand, Debug)]
+pub(crate) enum ConfigCommands {
+    /// check the config files and report every problem
+    Validate(Validate),
+    /// print the JSON Schema of the config file, e.g. for the YAML language server
+    Schema,
 }
 
 #[derive(Args, Debug)]
@@ -26,27 +64,164 @@ pub(crate) struct Log {
     #[arg(long, short)]
     pub(crate) config: Option<Box<Path>>,
 
-    /// id for which to execute the operation
+    #[arg(long)]
+    pub(crate) all_config: Option<Box<Path>>,
+
+    #[arg(long)]
+    pub(crate) all_id: bool,
+
+    #[command(flatten)]
+    pub(crate) select: Selector,
+
+    #[command(flatten)]
+    pub(crate) filter: HostFilter,
+
+    /// Maximum number of executables handled at the same time
+    #[arg(long, default_value_t = 1)]
+    pub(crate) parallel: usize,
+}
+
+#[derive(Args, Debug)]
+pub(crate) struct Run {
+    /// Path to the config file
     #[arg(long, short)]
-    pub(crate) id: Option<String>,
+    pub(crate) config: Option<Box<Path>>,
 
     #[arg(long)]
     pub(crate) all_config: Option<Box<Path>>,
 
     #[arg(long)]
     pub(crate) all_id: bool,
+
+    #[command(flatten)]
+    pub(crate) select: Selector,
+
+    #[command(flatten)]
+    pub(crate) filter: HostFilter,
+
+    /// Maximum number of executables handled at the same time
+    #[arg(long, default_value_t = 1)]
+    pub(crate) parallel: usize,
+
+    /// Rolling deploy in batches of N executables or a percentage such as 25%
+    #[arg(long)]
+    pub(crate) batch_size: Option<BatchSize>,
+
+    /// Halt the rolling deploy once more than this many executables failed
+    #[arg(long, default_value_t = 0, requires = "batch_size")]
+    pub(crate) max_failures: usize,
+
+    /// Roll back the batch to the previous version when the rolling deploy halts
+    #[arg(long, requires = "batch_size")]
+    pub(crate) rollback: bool,
+
+    /// Build the executables with a build section before deploying
+    #[arg(long)]
+    pub(crate) build: bool,
+
+    /// Inspect the remote state and print the deploy plan without changing anything
+    #[arg(long, conflicts_with = "batch_size")]
+    pub(crate) dry_run: bool,
 }
 
 #[derive(Args, Debug)]
-pub(crate) struct Run {
+pub(crate) struct Status {
     /// Path to the config file
     #[arg(long, short)]
     pub(crate) config: Option<Box<Path>>,
 
-    /// id for which to execute the operation
+    #[arg(long)]
+    pub(crate) all_config: Option<Box<Path>>,
+
+    #[arg(long)]
+    pub(crate) all_id: bool,
+
+    #[command(flatten)]
+    pub(crate) select: Selector,
+
+    #[command(flatten)]
+    pub(crate) filter: HostFilter,
+
+    /// Maximum number of executables handled at the same time
+    #[arg(long, default_value_t = 1)]
+    pub(crate) parallel: usize,
+}
+
+#[derive(Args, Debug)]
+pub(crate) struct Watch {
+    /// Path to the config file
     #[arg(long, short)]
-    pub(crate) id: Option<String>,
+    pub(crate) config: Option<Box<Path>>,
 
     #[arg(long)]
     pub(crate) all_id: bool,
+
+    #[command(flatten)]
+    pub(crate) select: Selector,
+
+    #[command(flatten)]
+    pub(crate) filter: HostFilter,
+
+    /// Run the build step when the source files change
+    #[arg(long)]
+    pub(crate) build: bool,
+
+    /// Source files and directories watched with --build
+    #[arg(long, value_delimiter = ',', default_values = ["src", "Cargo.toml"])]
+    pub(crate) source: Vec<PathBuf>,
+
+    /// Wait until the files stop changing for this long before acting
+    #[arg(long, default_value_t = 500)]
+    pub(crate) debounce_ms: u64,
+
+    /// How often the files and the log are polled
+    #[arg(long, default_value_t = 1000)]
+    pub(crate) interval_ms: u64,
+}
+
+#[derive(Args, Debug)]
+pub(crate) struct Selector {
+    /// ids for which to execute the operation, glob patterns such as 'api-*' are allowed
+    #[arg(long, short, value_delimiter = ',')]
+    pub(crate) id: Vec<String>,
+
+    /// Only operate on executables with one of these tags
+    #[arg(long, value_delimiter = ',')]
+    pub(crate) tag: Vec<String>,
+
+    /// Skip executables whose id or tag matches one of these patterns
+    #[arg(long, value_delimiter = ',')]
+    pub(crate) exclude: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+pub(crate) struct HostFilter {
+    /// Only operate on these hosts, by name in the inventory or address
+    #[arg(long, value_delimiter = ',')]
+    pub(crate) host: Vec<String>,
+
+    /// Only operate on hosts in these groups
+    #[arg(long, value_delimiter = ',')]
+    pub(crate) group: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+pub(crate) struct Validate {
+    /// Path to the config file
+    #[arg(long, short)]
+    pub(crate) config: Option<Box<Path>>,
+
+    #[arg(long)]
+    pub(crate) all_config: Option<Box<Path>>,
+
+    /// Also check that every host accepts the ssh account
+    #[arg(long)]
+    pub(crate) check_hosts: bool,
+}
+
+#[derive(Args, Debug)]
+pub(crate) struct Init {
+    /// Path of the config file to create
+    #[arg(long, short)]
+    pub(crate) config: Option<Box<Path>>,
 }
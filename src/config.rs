@@ -1,77 +1,1301 @@
+use anyhow::{anyhow, bail};
+use schemars::schema::{RootSchema, Schema};
+use schemars::{schema_for, JsonSchema};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use std::path::Path;
-use std::process::exit;
+use serde_yaml::{Mapping, Value};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::env;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub(crate) struct SSHAccount {
     pub(crate) addr: String,
     pub(crate) username: String,
+    ///不替换 ${VAR} 和 $$, 原样使用
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub(crate) password: String,
+    ///私钥文件, 设置后使用私钥认证, password 为私钥的密码
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) private_key: Option<Box<Path>>,
+    ///主机的模板变量, 例如端口
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) vars: BTreeMap<String, String>,
+    ///启用 ssh 传输压缩, 适合慢速链路, 服务器不支持时不压缩
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub(crate) compress: bool,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub(crate) struct Executable {
     pub(crate) id: String,
     pub(crate) executable_file_name: String,
+    ///本地可执行文件所在目录, 配置了 cargo 编译时默认为编译输出目录
+    #[serde(default = "empty_path", skip_serializing_if = "is_empty_path")]
     pub(crate) local_path: Box<Path>,
     pub(crate) remote_path: Box<Path>,
+    ///标签, 用于 --tag 和 --exclude 选择可执行文件
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) tags: Vec<String>,
+    ///启动参数
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) args: Vec<String>,
+    ///启动时设置的环境变量
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) env: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub(crate) health_check: HealthCheck,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub(crate) hooks: Hooks,
+    ///和可执行文件一起部署的文件和目录
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) files: Vec<FileMapping>,
+    ///每个主机渲染后上传的模板文件
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) templates: Vec<Template>,
+    ///exe run --build 时先编译
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) build: Option<Build>,
+    ///上传可执行文件的方式
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub(crate) transfer: Transfer,
+    ///覆盖配置文件的 ssh_account, 没有指定 hosts 和 groups 时使用
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) ssh_account: Option<SSHAccount>,
+    ///部署到的主机名称, 对应 hosts 中的 key
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) hosts: Vec<String>,
+    ///部署到的主机组名称, 对应 groups 中的 key
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) groups: Vec<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+///启动后的健康检查
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub(crate) struct HealthCheck {
+    ///启动后等待多少秒再检查
+    pub(crate) delay_secs: u64,
+    ///在远程服务器上执行的检查命令, 退出码为 0 表示健康, 未设置时检查进程是否在运行,
+    ///不替换 ${VAR}, 由远程 shell 展开
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) command: Option<String>,
+    ///检查失败时的重试次数
+    pub(crate) retries: u32,
+    ///两次检查之间间隔的秒数
+    pub(crate) interval_secs: u64,
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        Self {
+            delay_secs: 0,
+            command: None,
+            retries: 0,
+            interval_secs: 1,
+        }
+    }
+}
+
+fn empty_path() -> Box<Path> {
+    PathBuf::new().into_boxed_path()
+}
+
+fn is_empty_path(path: &Path) -> bool {
+    path.as_os_str().is_empty()
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+fn is_default<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
+}
+
+///和可执行文件一起部署的文件
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub(crate) struct FileMapping {
+    ///本地文件或目录, 可以使用通配符, 目录会递归上传
+    pub(crate) local: String,
+    ///远程路径, 相对路径相对于 remote_path, 以 / 结尾或匹配多个文件时为目录
+    pub(crate) remote: String,
+    ///八进制的文件权限, 例如 "644", 默认使用本地文件的权限
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) mode: Option<String>,
+    #[serde(default)]
+    pub(crate) overwrite: Overwrite,
+}
+
+///模板文件, 使用 ${VAR} 引用变量, 变量依次从主机的 vars, 配置的 vars 和环境变量中查找,
+///另外可以使用 host, addr, ip 和 id
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub(crate) struct Template {
+    ///本地模板文件
+    pub(crate) local: PathBuf,
+    ///远程路径, 相对路径相对于 remote_path
+    pub(crate) remote: String,
+    ///八进制的文件权限, 例如 "644", 默认使用本地文件的权限
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) mode: Option<String>,
+}
+
+///远程文件已存在时是否覆盖
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Overwrite {
+    #[default]
+    Always,
+    ///保留远程服务器上已有的文件, 例如在服务器上修改过的配置
+    Never,
+}
+
+///展开后的一个文件
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct CompanionFile {
+    pub(crate) local: PathBuf,
+    pub(crate) remote: PathBuf,
+    pub(crate) mode: i32,
+    pub(crate) overwrite: Overwrite,
+    ///模板渲染后的内容, 为 None 时上传本地文件
+    pub(crate) rendered: Option<String>,
+}
+
+fn parse_mode(mode: &Option<String>) -> crate::Result<Option<i32>> {
+    mode.as_ref()
+        .map(|mode| i32::from_str_radix(mode, 8).map_err(|_| anyhow!("无效的文件权限: {}", mode)))
+        .transpose()
+}
+
+///渲染模板文件, 变量先从 vars 中查找, 再从环境变量中查找
+pub(crate) fn render_templates(
+    templates: &[Template],
+    remote_path: &Path,
+    vars: &BTreeMap<String, String>,
+) -> crate::Result<Vec<CompanionFile>> {
+    let lookup = |name: &str| vars.get(name).cloned().or_else(|| env::var(name).ok());
+    templates
+        .iter()
+        .map(|t| {
+            let source = std::fs::read_to_string(&t.local)
+                .map_err(|e| anyhow!("读取模板 {} 失败: {}", t.local.display(), e))?;
+            let rendered = interpolate(&source, &lookup)
+                .map_err(|name| anyhow!("模板 {} 中没有定义变量: {}", t.local.display(), name))?;
+            Ok(CompanionFile {
+                local: t.local.clone(),
+                remote: remote_path.join(&t.remote),
+                mode: parse_mode(&t.mode)?.unwrap_or_else(|| local_mode(&t.local)),
+                overwrite: Overwrite::Always,
+                rendered: Some(rendered),
+            })
+        })
+        .collect()
+}
+
+///展开 files 中的通配符和目录, 返回每个需要上传的文件
+pub(crate) fn expand_files(
+    files: &[FileMapping],
+    remote_path: &Path,
+) -> crate::Result<Vec<CompanionFile>> {
+    let mut expanded = Vec::new();
+    for file in files {
+        let mode = parse_mode(&file.mode)?;
+        let paths = glob::glob(&file.local)
+            .map_err(|e| anyhow!("无效的匹配模式 {}: {}", file.local, e))?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        if paths.is_empty() {
+            bail!("没有匹配的本地文件: {}", file.local);
+        }
+        let remote = remote_path.join(&file.remote);
+        let into_dir = file.remote.ends_with('/') || paths.len() > 1;
+        for path in paths {
+            let remote = match into_dir {
+                true => remote.join(path.file_name().unwrap_or_default()),
+                false => remote.clone(),
+            };
+            let mut push = |local: PathBuf, remote: PathBuf| {
+                expanded.push(CompanionFile {
+                    mode: mode.unwrap_or_else(|| local_mode(&local)),
+                    local,
+                    remote,
+                    overwrite: file.overwrite,
+                    rendered: None,
+                })
+            };
+            if path.is_dir() {
+                walk_dir(&path, &remote, &mut push)?;
+            } else {
+                push(path, remote);
+            }
+        }
+    }
+    Ok(expanded)
+}
+
+///递归遍历目录中的文件
+fn walk_dir(
+    local: &Path,
+    remote: &Path,
+    f: &mut impl FnMut(PathBuf, PathBuf),
+) -> crate::Result<()> {
+    let mut entries = std::fs::read_dir(local)?.collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let remote = remote.join(entry.file_name());
+        if entry.path().is_dir() {
+            walk_dir(&entry.path(), &remote, f)?;
+        } else {
+            f(entry.path(), remote);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn local_mode(path: &Path) -> i32 {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata()
+        .map(|m| (m.permissions().mode() & 0o777) as i32)
+        .unwrap_or(0o644)
+}
+
+#[cfg(not(unix))]
+fn local_mode(_path: &Path) -> i32 {
+    0o644
+}
+
+///编译配置, 默认使用 cargo build, 设置 command 时执行 command
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub(crate) struct Build {
+    ///cargo build -p 的 package
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) package: Option<String>,
+    ///cargo profile
+    pub(crate) profile: String,
+    ///交叉编译的目标平台, 例如 aarch64-unknown-linux-musl
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) target: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) features: Vec<String>,
+    ///代替 cargo build 在本地执行的命令, 需要同时设置 local_path, 不替换 ${VAR}, 由 shell 展开
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) command: Option<String>,
+}
+
+impl Default for Build {
+    fn default() -> Self {
+        Self {
+            package: None,
+            profile: "release".to_owned(),
+            target: None,
+            features: Vec::new(),
+            command: None,
+        }
+    }
+}
+
+impl Build {
+    ///cargo 编译输出目录, 使用 command 时为 None
+    pub(crate) fn output_dir(&self) -> Option<PathBuf> {
+        if self.command.is_some() {
+            return None;
+        }
+        let mut dir = env::var_os("CARGO_TARGET_DIR")
+            .map(PathBuf::from)
+            .unwrap_or(PathBuf::from("target"));
+        if let Some(target) = &self.target {
+            dir.push(target);
+        }
+        dir.push(match self.profile.as_str() {
+            "dev" | "test" => "debug",
+            "bench" => "release",
+            profile => profile,
+        });
+        Some(dir)
+    }
+}
+
+///上传可执行文件的方式
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub(crate) struct Transfer {
+    ///远程服务器上已有旧版本时只上传变化的块, 失败时上传整个文件, 默认关闭,
+    ///计算远程文件的校验和需要读取整个文件, 配置较低的服务器上可能比直接上传更慢
+    pub(crate) delta: bool,
+}
+
+///部署过程中执行的命令
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub(crate) struct Hooks {
+    ///上传前在本地执行, 例如编译和测试
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) before_upload: Vec<Hook>,
+    ///停止进程前在远程服务器的 remote_path 中执行, 例如摘除流量和备份数据库
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) before_stop: Vec<Hook>,
+    ///启动并通过健康检查后在远程服务器的 remote_path 中执行, 例如数据库迁移和预热缓存
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) after_start: Vec<Hook>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub(crate) struct Hook {
+    ///shell 命令, 不替换 ${VAR}, 可以直接使用 shell 变量
+    pub(crate) command: String,
+    ///命令失败时是否继续部署
+    #[serde(default)]
+    pub(crate) on_failure: OnFailure,
+    ///远程 hook 的最长执行秒数, 默认 600, 本地 hook 不限制
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) timeout_secs: Option<u64>,
+}
+
+impl Hook {
+    ///远程 hook 的超时
+    pub(crate) fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs.unwrap_or(600))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum OnFailure {
+    ///停止部署
+    #[default]
+    Abort,
+    ///忽略错误继续部署
+    Continue,
+}
+
+///主机清单, 可以写在配置文件中, 也可以放在多个配置文件共享的清单文件中
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub(crate) struct Inventory {
+    ///主机名称 -> 账号
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) hosts: BTreeMap<String, SSHAccount>,
+    ///主机组名称 -> 主机名称列表
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) groups: BTreeMap<String, Vec<String>>,
+}
+
+///配置文件
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub(crate) struct Config {
-    pub(crate) ssh_account: SSHAccount,
+    ///继承的配置文件, 可以是一个路径或路径列表, 相对路径相对于配置文件所在目录
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[schemars(with = "Extends")]
+    pub(crate) extends: Vec<Box<Path>>,
+    ///所有可执行文件共用的默认值, 可执行文件中的同名字段优先
+    #[serde(default, skip_serializing_if = "Mapping::is_empty")]
+    #[schemars(with = "BTreeMap<String, serde_json::Value>")]
+    pub(crate) defaults: Mapping,
+    ///配置中 ${VAR} 使用的变量, 优先级低于命令行 --set, 高于环境变量
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) vars: BTreeMap<String, String>,
+    ///环境配置, 使用 --profile 选择后合并到配置中
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    #[schemars(with = "BTreeMap<String, BTreeMap<String, serde_json::Value>>")]
+    pub(crate) profiles: BTreeMap<String, Mapping>,
+    ///没有指定 hosts 或 groups 的可执行文件使用的账号
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) ssh_account: Option<SSHAccount>,
+    ///共享的主机清单文件, 相对路径相对于配置文件所在目录
+    #[serde(rename = "inventory", default, skip_serializing_if = "Option::is_none")]
+    pub(crate) inventory_file: Option<Box<Path>>,
+    #[serde(flatten)]
+    pub(crate) inventory: Inventory,
     pub(crate) executable_config_list: Vec<Executable>,
 }
 
+///extends 在配置文件中可以是一个路径或路径列表, 只用于生成 JSON Schema
+#[allow(dead_code)]
+#[derive(JsonSchema)]
+#[serde(untagged)]
+enum Extends {
+    One(PathBuf),
+    Many(Vec<PathBuf>),
+}
+
+///配置文件的 JSON Schema,
+///extends, defaults 和 profiles 可以补全其他字段, 所以只有可执行文件的 id 是必填的
+pub(crate) fn schema() -> RootSchema {
+    let mut schema = schema_for!(Config);
+    //flatten 的 Inventory 会覆盖 Config 的说明
+    schema.schema.metadata().description = Some("exe 的配置文件".to_owned());
+    schema.schema.object().required.clear();
+    if let Some(Schema::Object(executable)) = schema.definitions.get_mut("Executable") {
+        executable.object().required = BTreeSet::from(["id".to_owned()]);
+    }
+    schema
+}
+
+///加载配置时由命令行指定的选项
+#[derive(Debug, Clone, Default)]
+pub(crate) struct LoadOptions {
+    ///使用的环境配置
+    pub(crate) profile: Option<String>,
+    ///命令行 --set 设置的变量
+    pub(crate) vars: Vec<(String, String)>,
+}
+
 impl Config {
-    pub(crate) async fn load(config_path: impl AsRef<Path>) -> crate::Result<Config> {
-        let mut config = Config {
-            ssh_account: SSHAccount {
-                addr: "ip:port".to_owned(),
-                username: "username".to_owned(),
-                password: "password".to_owned(),
-            },
-            executable_config_list: vec![Executable {
-                id: "default".to_owned(),
-                executable_file_name: "executable_file_name".to_owned(),
-                local_path: Box::from(Path::new("local_path")),
-                remote_path: Box::from(Path::new(
-                    "remote_path # need end with /(unix) or \\(windows)",
-                )),
-            }],
-        };
+    pub(crate) async fn load(
+        config_path: impl AsRef<Path>,
+        options: &LoadOptions,
+    ) -> crate::Result<Config> {
         let config_path = config_path.as_ref();
-        if config_path.exists() {
-            config = serde_yaml::from_str(&std::fs::read_to_string(config_path)?)?;
-        } else {
-            let data = serde_yaml::to_string(&config)?;
-            std::fs::write(config_path, data)?;
+        if !config_path.exists() {
+            bail!(
+                "配置文件 {} 不存在, 请运行 exe init 生成",
+                config_path.display()
+            );
+        }
+
+        let (config, source) = Config::parse(config_path, options)?;
+        //判断配置未修改 就返回错误
+        let problems = config.check_placeholders(config_path, &source);
+        if !problems.is_empty() {
+            let problems: Vec<_> = problems.iter().map(|p| p.to_string()).collect();
+            bail!("请修改配置:\n{}", problems.join("\n"));
+        }
+        Ok(config)
+    }
+
+    ///读取并解析配置文件和主机清单, 返回配置和配置文件内容
+    fn parse(
+        config_path: &Path,
+        options: &LoadOptions,
+    ) -> std::result::Result<(Config, String), ValidationError> {
+        let source = std::fs::read_to_string(config_path)
+            .map_err(|e| ValidationError::new(config_path, None, e.to_string()))?;
+        let mut value = Config::load_value(config_path, &source, &mut Vec::new())?;
+        //没有选择的环境配置不替换变量
+        let profiles = value
+            .as_mapping_mut()
+            .and_then(|m| m.remove("profiles"))
+            .unwrap_or_default();
+        if let Some(profile) = &options.profile {
+            let profile = profiles
+                .get(profile.as_str())
+                .cloned()
+                .ok_or(ValidationError::new(
+                    config_path,
+                    find_line(&source, 0, "profiles", None),
+                    format!("没有找到环境配置: {}", profile),
+                ))?;
+            merge_config(&mut value, profile);
+        }
+        interpolate_config(&mut value, &options.vars).map_err(|name| {
+            let line = source
+                .lines()
+                .position(|line| line.contains(&format!("${{{}", name)))
+                .map(|i| i + 1);
+            ValidationError::new(config_path, line, format!("没有定义变量: {}", name))
+        })?;
+        if let (Some(m), false) = (value.as_mapping_mut(), profiles.is_null()) {
+            m.insert("profiles".into(), profiles);
+        }
+        apply_defaults(&mut value);
+        let mut config: Config = serde_yaml::from_value(value)
+            .map_err(|e| ValidationError::from_yaml(config_path, e))?;
+        //模板中的变量同样可以用 --set 覆盖
+        config.vars.extend(options.vars.iter().cloned());
+        //配置了 cargo 编译且没有指定 local_path 时使用编译输出目录
+        for e in &mut config.executable_config_list {
+            let output_dir = e.build.as_ref().and_then(Build::output_dir);
+            if let (Some(output_dir), true) = (output_dir, is_empty_path(&e.local_path)) {
+                e.local_path = output_dir.into_boxed_path();
+            }
+        }
+
+        //inventory 在 load_value 中已经转换为相对于配置文件所在目录的路径
+        if let Some(inventory) = config.inventory_file.clone() {
+            let data = std::fs::read_to_string(&inventory).map_err(|e| {
+                let line = find_line(&source, 0, "inventory", None);
+                let message = format!("读取主机清单 {} 失败: {}", inventory.display(), e);
+                ValidationError::new(config_path, line, message)
+            })?;
+            let Inventory { hosts, groups } =
+                ConfigFormat::from_path(&inventory).parse(&inventory, &data)?;
+            //配置文件中的同名主机和主机组优先
+            hosts.into_iter().for_each(|(name, account)| {
+                config.inventory.hosts.entry(name).or_insert(account);
+            });
+            groups.into_iter().for_each(|(name, hosts)| {
+                config.inventory.groups.entry(name).or_insert(hosts);
+            });
+        }
+        Ok((config, source))
+    }
+
+    ///读取配置文件并依次合并继承的配置文件, stack 为正在读取的配置文件, 用于检查循环继承
+    fn load_value(
+        config_path: &Path,
+        source: &str,
+        stack: &mut Vec<PathBuf>,
+    ) -> std::result::Result<Value, ValidationError> {
+        let mut value: Value = ConfigFormat::from_path(config_path).parse(config_path, source)?;
+        let dir = config_path.parent().unwrap_or(Path::new(""));
+        let error = |key: &str, message: String| {
+            ValidationError::new(config_path, find_line(source, 0, key, None), message)
+        };
+
+        //相对路径改为相对于当前配置文件所在目录
+        if let Some(Value::String(inventory)) = value.get_mut("inventory") {
+            *inventory = dir.join(&inventory).to_string_lossy().into_owned();
+        }
+        let extends: Vec<PathBuf> = match value.get("extends") {
+            None | Some(Value::Null) => Vec::new(),
+            Some(Value::String(base)) => vec![dir.join(base)],
+            Some(Value::Sequence(bases)) => bases
+                .iter()
+                .map(|base| base.as_str().map(|base| dir.join(base)))
+                .collect::<Option<_>>()
+                .ok_or(error("extends", "extends 必须是路径或路径列表".to_owned()))?,
+            Some(_) => return Err(error("extends", "extends 必须是路径或路径列表".to_owned())),
         };
+        if let Some(mapping) = value.as_mapping_mut() {
+            let extends = extends
+                .iter()
+                .map(|base| Value::String(base.to_string_lossy().into_owned()))
+                .collect();
+            mapping.insert("extends".into(), Value::Sequence(extends));
+        }
+
+        let mut merged = Value::Mapping(Mapping::new());
+        stack.push(config_path.to_path_buf());
+        for base in extends {
+            if stack.contains(&base) {
+                return Err(error("extends", format!("循环继承: {}", base.display())));
+            }
+            let base_source = std::fs::read_to_string(&base).map_err(|e| {
+                error(
+                    "extends",
+                    format!("读取继承的配置 {} 失败: {}", base.display(), e),
+                )
+            })?;
+            let base_value = Config::load_value(&base, &base_source, stack)?;
+            merge_config(&mut merged, base_value);
+        }
+        stack.pop();
+        merge_config(&mut merged, value);
+        Ok(merged)
+    }
 
-        //判断配置未修改 就退出
-        if config.ssh_account.username == "username"
-            || config.ssh_account.password == "password"
-            || config.ssh_account.addr == "ip:port"
-        {
-            println!(
-                "请修改{}中的 username, password 或 addr",
-                config_path.file_name().unwrap().to_str().unwrap()
+    ///检查未修改的默认值
+    fn check_placeholders(&self, config_path: &Path, source: &str) -> Vec<ValidationError> {
+        let mut problems = Vec::new();
+        let accounts = self
+            .ssh_account
+            .iter()
+            .chain(self.inventory.hosts.values())
+            .chain(
+                self.executable_config_list
+                    .iter()
+                    .filter_map(|e| e.ssh_account.as_ref()),
             );
-            exit(0);
+        for a in accounts {
+            let fields = [
+                ("addr", &a.addr, "ip:port"),
+                ("username", &a.username, "username"),
+                ("password", &a.password, "password"),
+            ];
+            for (key, _, placeholder) in fields.iter().filter(|(_, v, p)| v == p) {
+                problems.push(ValidationError::new(
+                    config_path,
+                    find_line(source, 0, key, Some(placeholder)),
+                    format!("请修改 {}", key),
+                ));
+            }
+        }
+
+        for e in &self.executable_config_list {
+            let from = find_line(source, 0, "id", Some(&e.id)).unwrap_or(1) - 1;
+            let fields = [
+                (
+                    "executable_file_name",
+                    e.executable_file_name == "executable_file_name",
+                ),
+                ("local_path", e.local_path.as_os_str() == "local_path"),
+                (
+                    "remote_path",
+                    e.remote_path.to_string_lossy().starts_with("remote_path"),
+                ),
+            ];
+            for (key, _) in fields.iter().filter(|(_, unchanged)| *unchanged) {
+                problems.push(ValidationError::new(
+                    config_path,
+                    find_line(source, from, key, None),
+                    format!("请修改 {} 的 {}", e.id, key),
+                ));
+            }
+        }
+        problems
+    }
+
+    ///检查配置文件, 返回发现的所有问题, 解析失败时 Config 为 None
+    pub(crate) fn validate(
+        config_path: &Path,
+        options: &LoadOptions,
+    ) -> (Option<Config>, Vec<ValidationError>) {
+        let (config, source) = match Config::parse(config_path, options) {
+            Ok(v) => v,
+            Err(e) => return (None, vec![e]),
         };
+        let mut problems = config.check_placeholders(config_path, &source);
 
-        if let Some(e) = config.executable_config_list.get(0) {
-            if e.executable_file_name == "executable_file_name"
-                || e.local_path.as_os_str() == "local_path"
-                || e.remote_path.as_os_str() == "remote_path"
-            {
-                println!(
-                    "请修改{}中的 executable_file_name, local_path 或 remote_path",
-                    config_path.file_name().unwrap().to_str().unwrap()
+        let mut ids = HashSet::new();
+        let mut id_lines = 0;
+        for e in &config.executable_config_list {
+            let line = find_line(&source, id_lines, "id", Some(&e.id));
+            id_lines = line.unwrap_or(id_lines);
+            let from = line.unwrap_or(1) - 1;
+            let mut problem = |key: &str, message: String| {
+                let line = find_line(&source, from, key, None).or(line);
+                problems.push(ValidationError::new(config_path, line, message));
+            };
+
+            if e.id.is_empty() {
+                problem("id", "id 不能为空".to_owned());
+            } else if !ids.insert(e.id.as_str()) {
+                problem("id", format!("重复的 id: {}", e.id));
+            }
+            if e.executable_file_name.is_empty() {
+                problem(
+                    "executable_file_name",
+                    format!("{} 的 executable_file_name 不能为空", e.id),
+                );
+            }
+            //配置了编译时本地文件由 exe run --build 生成
+            let local_file = e.local_path.join(&e.executable_file_name);
+            if is_empty_path(&e.local_path) {
+                problem("local_path", format!("{} 的 local_path 不能为空", e.id));
+            } else if e.build.is_none() && !local_file.is_file() {
+                problem(
+                    "local_path",
+                    format!("{} 的本地文件不存在: {}", e.id, local_file.display()),
+                );
+            }
+            for template in e.templates.iter().filter(|t| !t.local.is_file()) {
+                problem(
+                    "templates",
+                    format!("{} 的模板文件不存在: {}", e.id, template.local.display()),
                 );
-                exit(0);
+            }
+            if let Err(err) = expand_files(&e.files, &e.remote_path) {
+                problem("files", format!("{} 的 files 配置错误: {:#}", e.id, err));
+            }
+            if !e.remote_path.to_string_lossy().starts_with('/') {
+                problem(
+                    "remote_path",
+                    format!(
+                        "{} 的 remote_path 必须是绝对路径: {}",
+                        e.id,
+                        e.remote_path.display()
+                    ),
+                );
+            }
+            if let Err(err) = config.resolve_hosts(e) {
+                let key = if e.groups.is_empty() {
+                    "hosts"
+                } else {
+                    "groups"
+                };
+                problem(key, format!("{}: {:#}", e.id, err));
             }
         }
-        Ok(config)
+        (Some(config), problems)
+    }
+
+    ///可执行文件部署到的主机, 返回 (主机名称, 账号),
+    ///没有指定 hosts 和 groups 时使用可执行文件或配置文件的 ssh_account,
+    ///都没有时返回错误, 不会部署到 inventory 中的所有主机
+    pub(crate) fn resolve_hosts(&self, e: &Executable) -> crate::Result<Vec<(String, SSHAccount)>> {
+        if e.hosts.is_empty() && e.groups.is_empty() {
+            return match e.ssh_account.as_ref().or(self.ssh_account.as_ref()) {
+                Some(ssh_account) => Ok(vec![(ssh_account.addr.clone(), ssh_account.clone())]),
+                None => Err(anyhow!("{} 没有指定 hosts, groups 或 ssh_account", e.id)),
+            };
+        }
+
+        let mut names: Vec<&String> = e.hosts.iter().collect();
+        for group in &e.groups {
+            let hosts = self
+                .inventory
+                .groups
+                .get(group)
+                .ok_or(anyhow!("没有找到主机组: {}", group))?;
+            names.extend(hosts);
+        }
+        let mut hosts = Vec::new();
+        for name in names {
+            if hosts.iter().any(|(n, _)| n == name) {
+                continue;
+            }
+            let account = self
+                .inventory
+                .hosts
+                .get(name)
+                .ok_or(anyhow!("没有找到主机: {}", name))?;
+            hosts.push((name.clone(), account.clone()));
+        }
+        Ok(hosts)
+    }
+
+    ///主机所在的主机组
+    pub(crate) fn host_groups(&self, host: &str) -> Vec<String> {
+        self.inventory
+            .groups
+            .iter()
+            .filter(|(_, hosts)| hosts.iter().any(|h| h == host))
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    ///渲染模板使用的变量, 主机的 vars 优先于配置的 vars
+    pub(crate) fn template_vars(
+        &self,
+        e: &Executable,
+        host: &str,
+        ssh_account: &SSHAccount,
+    ) -> BTreeMap<String, String> {
+        let mut vars = self.vars.clone();
+        vars.extend(ssh_account.vars.clone());
+        let ip = ssh_account
+            .addr
+            .rsplit_once(':')
+            .map_or(&*ssh_account.addr, |(ip, _)| ip);
+        vars.insert("host".to_owned(), host.to_owned());
+        vars.insert("addr".to_owned(), ssh_account.addr.clone());
+        vars.insert("ip".to_owned(), ip.to_owned());
+        vars.insert("id".to_owned(), e.id.clone());
+        vars
+    }
+}
+
+///配置文件格式, 按扩展名区分, 未知的扩展名按 yaml 处理
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ConfigFormat {
+    Yaml,
+    Toml,
+    Json,
+}
+
+impl ConfigFormat {
+    ///支持的配置文件扩展名
+    pub(crate) const EXTENSIONS: [&'static str; 4] = ["yaml", "yml", "toml", "json"];
+
+    pub(crate) fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => ConfigFormat::Toml,
+            Some("json") => ConfigFormat::Json,
+            _ => ConfigFormat::Yaml,
+        }
+    }
+
+    fn parse<T: DeserializeOwned>(
+        self,
+        file: &Path,
+        source: &str,
+    ) -> std::result::Result<T, ValidationError> {
+        match self {
+            ConfigFormat::Yaml => {
+                serde_yaml::from_str(source).map_err(|e| ValidationError::from_yaml(file, e))
+            }
+            ConfigFormat::Toml => toml::from_str(source).map_err(|e| {
+                let line = e
+                    .span()
+                    .map(|span| source[..span.start].matches('\n').count() + 1);
+                ValidationError::new(file, line, e.message().to_owned())
+            }),
+            ConfigFormat::Json => serde_json::from_str(source)
+                .map_err(|e| ValidationError::new(file, Some(e.line()), e.to_string())),
+        }
+    }
+
+    pub(crate) fn to_string(self, value: &impl Serialize) -> crate::Result<String> {
+        Ok(match self {
+            ConfigFormat::Yaml => serde_yaml::to_string(value)?,
+            ConfigFormat::Toml => toml::to_string(value)?,
+            ConfigFormat::Json => serde_json::to_string_pretty(value)? + "\n",
+        })
+    }
+}
+
+///配置文件中的一个问题
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct ValidationError {
+    pub(crate) file: PathBuf,
+    ///行号, 从 1 开始
+    pub(crate) line: Option<usize>,
+    pub(crate) message: String,
+}
+
+impl ValidationError {
+    pub(crate) fn new(file: &Path, line: Option<usize>, message: String) -> Self {
+        Self {
+            file: file.to_path_buf(),
+            line,
+            message,
+        }
+    }
+
+    fn from_yaml(file: &Path, e: serde_yaml::Error) -> Self {
+        let line = e.location().map(|l| l.line());
+        Self::new(file, line, e.to_string())
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{}:{}: {}", self.file.display(), line, self.message),
+            None => write!(f, "{}: {}", self.file.display(), self.message),
+        }
+    }
+}
+
+///合并配置, overlay 中的值优先, overlay 的 executable_config_list 中的可执行文件和 base 中 id 相同的合并为一个,
+///同一个文件中重复的 id 保留, 由 validate 报告
+fn merge_config(base: &mut Value, mut overlay: Value) {
+    let executables = overlay
+        .as_mapping_mut()
+        .and_then(|m| m.remove("executable_config_list"));
+    if let (Some(Value::Sequence(executables)), Some(base)) = (executables, base.as_mapping_mut()) {
+        let base_executables = base
+            .entry("executable_config_list".into())
+            .or_insert(Value::Sequence(Vec::new()));
+        match base_executables {
+            Value::Sequence(base_executables) => {
+                let base_len = base_executables.len();
+                for e in executables {
+                    let same_id = base_executables[..base_len]
+                        .iter_mut()
+                        .find(|b| b.get("id").is_some() && b.get("id") == e.get("id"));
+                    match same_id {
+                        Some(b) => merge_value(b, e),
+                        None => base_executables.push(e),
+                    }
+                }
+            }
+            base_executables => *base_executables = Value::Sequence(executables),
+        }
+    }
+    merge_value(base, overlay);
+}
+
+///递归合并 mapping, 其他类型直接使用 overlay 的值
+fn merge_value(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Mapping(base), Value::Mapping(overlay)) => {
+            for (k, v) in overlay {
+                match base.get_mut(&k) {
+                    Some(b) => merge_value(b, v),
+                    None => {
+                        base.insert(k, v);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+///把 defaults 合并到每个可执行文件中
+fn apply_defaults(config: &mut Value) {
+    let Some(defaults) = config.get("defaults").cloned() else {
+        return;
+    };
+    if let Some(Value::Sequence(executables)) = config.get_mut("executable_config_list") {
+        for e in executables.iter_mut() {
+            let mut merged = defaults.clone();
+            merge_value(&mut merged, e.clone());
+            *e = merged;
+        }
+    }
+}
+
+///替换配置中字符串的变量, 变量优先级: 命令行 --set, vars, 环境变量,
+///command 字段是 shell 命令, 其中的 ${VAR} 留给 shell 展开, password 字段原样使用,
+///出错时返回没有定义的变量名
+fn interpolate_config(
+    config: &mut Value,
+    set: &[(String, String)],
+) -> std::result::Result<(), String> {
+    let from_set = |name: &str| {
+        set.iter()
+            .rev()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.clone())
+    };
+    let from_env = |name: &str| from_set(name).or_else(|| env::var(name).ok());
+
+    //vars 自身只能使用命令行变量和环境变量
+    let mut vars = BTreeMap::new();
+    if let Some(Value::Mapping(mapping)) = config.get_mut("vars") {
+        for (k, v) in mapping.iter_mut() {
+            if let Value::String(s) = v {
+                *s = interpolate(s, &from_env)?;
+            }
+            if let (Some(k), Some(v)) = (k.as_str(), v.as_str()) {
+                vars.insert(k.to_owned(), v.to_owned());
+            }
+        }
+    }
+    let lookup = |name: &str| {
+        from_set(name)
+            .or_else(|| vars.get(name).cloned())
+            .or_else(|| env::var(name).ok())
+    };
+    interpolate_value(config, &lookup)
+}
+
+///不替换变量的字段
+const NOT_INTERPOLATED: [&str; 2] = ["command", "password"];
+
+fn interpolate_value(
+    value: &mut Value,
+    lookup: &impl Fn(&str) -> Option<String>,
+) -> std::result::Result<(), String> {
+    match value {
+        Value::String(s) => *s = interpolate(s, lookup)?,
+        Value::Sequence(seq) => {
+            for v in seq {
+                interpolate_value(v, lookup)?;
+            }
+        }
+        Value::Mapping(mapping) => {
+            for (k, v) in mapping.iter_mut() {
+                if !k.as_str().is_some_and(|k| NOT_INTERPOLATED.contains(&k)) {
+                    interpolate_value(v, lookup)?;
+                }
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+///替换字符串中的 ${VAR} 和 ${VAR:-default}, $$ 为 $ 的转义, 出错时返回没有定义的变量名
+pub(crate) fn interpolate(
+    s: &str,
+    lookup: &impl Fn(&str) -> Option<String>,
+) -> std::result::Result<String, String> {
+    let mut output = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(i) = rest.find('$') {
+        output.push_str(&rest[..i]);
+        rest = &rest[i..];
+        if let Some(r) = rest.strip_prefix("$$") {
+            output.push('$');
+            rest = r;
+            continue;
+        }
+        let Some(end) = rest.strip_prefix("${").and_then(|r| r.find('}')) else {
+            output.push('$');
+            rest = &rest[1..];
+            continue;
+        };
+        let expr = &rest[2..end + 2];
+        let value = match expr.split_once(":-") {
+            Some((name, default)) => lookup(name).unwrap_or(default.to_owned()),
+            None => lookup(expr).ok_or(expr.to_owned())?,
+        };
+        output.push_str(&value);
+        rest = &rest[end + 3..];
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+///从第 from 行之后查找 `key: value` 所在的行, 行号从 1 开始, value 为 None 时只匹配 key
+pub(crate) fn find_line(
+    source: &str,
+    from: usize,
+    key: &str,
+    value: Option<&str>,
+) -> Option<usize> {
+    let unquote = |s: &str| s.trim().trim_matches(|c| c == '"' || c == '\'').to_owned();
+    source
+        .lines()
+        .enumerate()
+        .skip(from)
+        .find(|(_, line)| {
+            let line = line.trim_start().trim_start_matches("- ");
+            let Some((k, v)) = line.split_once([':', '=']) else {
+                return false;
+            };
+            unquote(k) == key && value.is_none_or(|value| unquote(v.trim_end_matches(',')) == value)
+        })
+        .map(|(i, _)| i + 1)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::{
+        apply_defaults, expand_files, find_line, interpolate, interpolate_config, merge_config,
+        render_templates, schema, Build, Config, ConfigFormat, FileMapping, LoadOptions, Overwrite,
+        Template,
+    };
+    use crate::test_util::TempDir;
+    use serde_yaml::Value;
+    use std::path::Path;
+
+    #[test]
+    fn interpolate_vars() {
+        let lookup = |name: &str| (name == "HOST").then(|| "web1".to_owned());
+        assert_eq!(
+            interpolate("/opt/${HOST}/", &lookup),
+            Ok("/opt/web1/".to_owned())
+        );
+        assert_eq!(interpolate("${PORT:-80}", &lookup), Ok("80".to_owned()));
+        assert_eq!(interpolate("$$HOME $1", &lookup), Ok("$HOME $1".to_owned()));
+        assert_eq!(interpolate("${NOPE}", &lookup), Err("NOPE".to_owned()));
+    }
+
+    #[test]
+    fn commands_and_passwords_are_not_interpolated() {
+        let mut config: Value = serde_yaml::from_str(
+            "vars: {dir: /opt/app}
+ssh_account: {addr: '${dir}', username: root, password: 'pa$$w0rd'}
+hosts:
+  web1: {addr: '10.0.0.1:22', username: root, password: 'a${b'}
+executable_config_list:
+- remote_path: ${dir}
+  hooks:
+    before_stop:
+    - command: cp data.db \"backup-${STAMP}.db\"
+  health_check: {command: 'test -e ${dir}'}
+",
+        )
+        .unwrap();
+        interpolate_config(&mut config, &[]).unwrap();
+        let e = &config["executable_config_list"][0];
+        assert_eq!(e["remote_path"], "/opt/app");
+        assert_eq!(
+            e["hooks"]["before_stop"][0]["command"],
+            "cp data.db \"backup-${STAMP}.db\""
+        );
+        assert_eq!(e["health_check"]["command"], "test -e ${dir}");
+        assert_eq!(config["ssh_account"]["addr"], "/opt/app");
+        assert_eq!(config["ssh_account"]["password"], "pa$$w0rd");
+        assert_eq!(config["hosts"]["web1"]["password"], "a${b");
+    }
+
+    #[test]
+    fn merge_extends_and_defaults() {
+        let mut base: Value = serde_yaml::from_str(
+            "defaults:\n  remote_path: /opt/app/\n  env: {RUST_LOG: info}\nexecutable_config_list:\n- id: a\n  args: [--a]\n- id: b\n",
+        )
+        .unwrap();
+        let overlay: Value = serde_yaml::from_str(
+            "executable_config_list:\n- id: a\n  env: {PORT: '80'}\n- id: c\n  remote_path: /opt/c/\n",
+        )
+        .unwrap();
+        merge_config(&mut base, overlay);
+        apply_defaults(&mut base);
+
+        let executables = base["executable_config_list"].as_sequence().unwrap();
+        assert_eq!(executables.len(), 3);
+        let a = &executables[0];
+        assert_eq!(a["remote_path"], "/opt/app/");
+        assert_eq!(a["args"][0], "--a");
+        assert_eq!(a["env"]["RUST_LOG"], "info");
+        assert_eq!(a["env"]["PORT"], "80");
+        assert_eq!(executables[2]["remote_path"], "/opt/c/");
+
+        //同一个文件中 id 重复的可执行文件不合并
+        let mut merged = Value::Mapping(Default::default());
+        let value: Value = serde_yaml::from_str(
+            "executable_config_list:\n- {id: a, remote_path: /opt/one}\n- {id: a, remote_path: /opt/two}\n",
+        )
+        .unwrap();
+        merge_config(&mut merged, value);
+        let executables = merged["executable_config_list"].as_sequence().unwrap();
+        assert_eq!(executables.len(), 2);
+        assert_eq!(executables[0]["remote_path"], "/opt/one");
+    }
+
+    #[test]
+    fn find_line_by_key_and_value() {
+        let source = "ssh_account:\n  addr: ip:port\nexecutable_config_list:\n- id: a\n  local_path: x\n- id: \"b\"\n  local_path: y\n";
+        assert_eq!(find_line(source, 0, "addr", Some("ip:port")), Some(2));
+        assert_eq!(find_line(source, 0, "id", Some("b")), Some(6));
+        assert_eq!(find_line(source, 5, "local_path", None), Some(7));
+        assert_eq!(find_line(source, 0, "id", Some("c")), None);
+    }
+
+    #[test]
+    fn parse_toml_and_json() {
+        let toml = "[[executable_config_list]]\nid = \"a\"\nlocal_path = \"x\"\n";
+        let json = "{\n  \"executable_config_list\": [\n    {\n      \"id\": \"a\",\n      \"local_path\": \"x\"\n    }\n  ]\n}\n";
+        for (name, source, line) in [("a.toml", toml, 2), ("a.json", json, 4)] {
+            let value: Value = ConfigFormat::from_path(Path::new(name))
+                .parse(Path::new(name), source)
+                .unwrap();
+            assert_eq!(value["executable_config_list"][0]["local_path"], "x");
+            assert_eq!(find_line(source, 0, "id", Some("a")), Some(line));
+        }
+        let e = ConfigFormat::Toml
+            .parse::<Value>(Path::new("a.toml"), "a = 1\n[b\n")
+            .unwrap_err();
+        assert_eq!(e.line, Some(2));
+
+        //默认值不写入配置文件
+        let config: Config = serde_yaml::from_str(
+            "executable_config_list: [{id: a, executable_file_name: a, remote_path: /opt/a, health_check: {retries: 3}}]",
+        )
+        .unwrap();
+        let json = ConfigFormat::Json.to_string(&config).unwrap();
+        assert!(json.contains("\"retries\": 3"));
+        for key in ["\"command\"", "hooks", "transfer", "null"] {
+            assert!(!json.contains(key), "{}", json);
+        }
+    }
+
+    #[test]
+    fn build_output_dir() {
+        let mut build = Build {
+            target: Some("aarch64-unknown-linux-musl".to_owned()),
+            ..Default::default()
+        };
+        let dir = build.output_dir().unwrap();
+        assert!(dir.ends_with("aarch64-unknown-linux-musl/release"));
+        build.profile = "dev".to_owned();
+        assert!(build
+            .output_dir()
+            .unwrap()
+            .ends_with("aarch64-unknown-linux-musl/debug"));
+        build.command = Some("make".to_owned());
+        assert_eq!(build.output_dir(), None);
+    }
+
+    #[test]
+    fn expand_files_glob_and_dir() {
+        let dir = TempDir::new("expand-files");
+        std::fs::create_dir_all(dir.join("static/css")).unwrap();
+        for file in [
+            "a.toml",
+            "b.toml",
+            "static/index.html",
+            "static/css/app.css",
+        ] {
+            std::fs::write(dir.join(file), "").unwrap();
+        }
+        let mapping = |local: &str, remote: &str| FileMapping {
+            local: dir.join(local).to_string_lossy().into_owned(),
+            remote: remote.to_owned(),
+            mode: Some("600".to_owned()),
+            overwrite: Overwrite::Never,
+        };
+        let files = expand_files(
+            &[
+                mapping("*.toml", "conf"),
+                mapping("a.toml", "app.toml"),
+                mapping("static", "public"),
+            ],
+            Path::new("/opt/app"),
+        )
+        .unwrap();
+        let remote: Vec<_> = files.iter().map(|f| f.remote.to_str().unwrap()).collect();
+        assert_eq!(
+            remote,
+            [
+                "/opt/app/conf/a.toml",
+                "/opt/app/conf/b.toml",
+                "/opt/app/app.toml",
+                "/opt/app/public/css/app.css",
+                "/opt/app/public/index.html",
+            ]
+        );
+        assert!(files.iter().all(|f| f.mode == 0o600));
+        assert!(expand_files(&[mapping("*.nope", "x")], Path::new("/opt")).is_err());
+    }
+
+    #[test]
+    fn render_template_with_host_vars() {
+        let config: Config = serde_yaml::from_str(
+            "vars: {port: '80', env: prod}\nhosts:\n  web1: {addr: '10.0.0.1:22', username: root, vars: {port: '8080'}}\nexecutable_config_list:\n- {id: api, executable_file_name: x, local_path: ., remote_path: /opt/api/}",
+        )
+        .unwrap();
+        let e = &config.executable_config_list[0];
+        let vars = config.template_vars(e, "web1", &config.inventory.hosts["web1"]);
+        let dir = TempDir::new("template");
+        let template = dir.join("app.toml");
+        std::fs::write(
+            &template,
+            "listen = \"${ip}:${port}\"\nname = \"${id}-${env}\"\n",
+        )
+        .unwrap();
+        let templates = [Template {
+            local: template.clone(),
+            remote: "app.toml".to_owned(),
+            mode: None,
+        }];
+        let files = render_templates(&templates, Path::new("/opt/api"), &vars).unwrap();
+        assert_eq!(files[0].remote, Path::new("/opt/api/app.toml"));
+        assert_eq!(
+            files[0].rendered.as_deref(),
+            Some("listen = \"10.0.0.1:8080\"\nname = \"api-prod\"\n")
+        );
+    }
+
+    #[test]
+    fn validate_reports_problems_with_lines() {
+        let dir = TempDir::new("validate");
+        std::fs::write(dir.join("app"), "").unwrap();
+        let source = format!(
+            "ssh_account: {{addr: '10.0.0.1:22', username: root, password: secret}}
+executable_config_list:
+- id: a
+  executable_file_name: app
+  local_path: {dir}
+  remote_path: /opt/a
+- id: b
+  executable_file_name: missing
+  local_path: {dir}
+  remote_path: opt/b
+- id: a
+  executable_file_name: app
+  local_path: {dir}
+  remote_path: /opt/a2
+",
+            dir = dir.display()
+        );
+        let config_path = dir.join("exe.yaml");
+        std::fs::write(&config_path, source).unwrap();
+
+        let (config, problems) = Config::validate(&config_path, &LoadOptions::default());
+        assert_eq!(config.unwrap().executable_config_list.len(), 3);
+        let problems: Vec<_> = problems
+            .iter()
+            .map(|p| (p.line, p.message.as_str()))
+            .collect();
+        let missing = format!("b 的本地文件不存在: {}", dir.join("missing").display());
+        assert_eq!(
+            problems,
+            [
+                (Some(9), missing.as_str()),
+                (Some(10), "b 的 remote_path 必须是绝对路径: opt/b"),
+                (Some(11), "重复的 id: a"),
+            ]
+        );
+    }
+
+    #[test]
+    fn schema_only_requires_id() {
+        let schema = serde_json::to_value(schema()).unwrap();
+        assert!(schema.get("required").is_none());
+        assert!(schema["properties"]["executable_config_list"].is_object());
+        assert!(schema["properties"]["hosts"].is_object());
+        let executable = &schema["definitions"]["Executable"];
+        assert_eq!(executable["required"], serde_json::json!(["id"]));
+        assert!(executable["properties"]["health_check"].is_object());
     }
 }
@@ -0,0 +1,267 @@
+use crate::ssh::ShellCommand;
+use crate::Result;
+use anyhow::{anyhow, bail};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::Path;
+
+///远程文件一个块的校验和
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct BlockSignature {
+    ///弱校验和, 可以滚动计算
+    pub(crate) weak: u32,
+    ///块的 sha256
+    pub(crate) strong: String,
+}
+
+///重建文件的一个步骤
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Op {
+    ///复制远程文件中从 block 开始的 count 个块
+    Copy { block: usize, count: usize },
+    ///复制差异数据中从 offset 开始的 len 个字节
+    Literal { offset: usize, len: usize },
+}
+
+///差异, data 为需要上传的数据
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Delta {
+    pub(crate) ops: Vec<Op>,
+    pub(crate) data: Vec<u8>,
+}
+
+///小于这个大小的文件直接上传
+pub(crate) const MIN_FILE_SIZE: usize = 256 * 1024;
+
+///按文件大小选择块大小, 4KB 到 64KB
+pub(crate) fn block_size(len: usize) -> usize {
+    (len / 2048)
+        .clamp(4096, 65536)
+        .next_power_of_two()
+        .min(65536)
+}
+
+///和 rsync 一样的弱校验和, a 为字节和, b 为 a 的前缀和, 都取低 16 位
+fn weak_checksum(data: &[u8]) -> (u32, u32) {
+    let (mut a, mut b) = (0u32, 0u32);
+    for &x in data {
+        a = a.wrapping_add(x as u32);
+        b = b.wrapping_add(a);
+    }
+    (a & 0xffff, b & 0xffff)
+}
+
+fn combine(a: u32, b: u32) -> u32 {
+    a | (b << 16)
+}
+
+///在远程服务器上计算每个完整块的弱校验和和 sha256 的命令,
+///只使用 od, awk, dd 和 sha256sum, 不需要在远程服务器上安装其他程序
+pub(crate) fn signature_command(file: &Path, block_size: usize) -> ShellCommand {
+    ShellCommand::default()
+        .var("f", file)
+        .var("B", block_size.to_string())
+        .raw(
+            "od -An -v -tu1 -- \"$f\" | awk -v B=$B '{for(i=1;i<=NF;i++){a+=$i;b+=a;n++;if(n==B){printf \"%d %d\\n\", a%65536, b%65536;a=0;b=0;n=0}}}'; \
+             echo --; \
+             n=$(( $(wc -c < \"$f\") / B )); i=0; \
+             while [ $i -lt $n ]; do dd if=\"$f\" bs=$B skip=$i count=1 2>/dev/null | sha256sum; i=$((i+1)); done",
+        )
+}
+
+///解析 signature_command 的输出
+pub(crate) fn parse_signatures(output: &str) -> Result<Vec<BlockSignature>> {
+    let (weak, strong) = output
+        .split_once("--\n")
+        .ok_or(anyhow!("无法解析块校验和"))?;
+    let weak: Vec<_> = weak.lines().collect();
+    let strong: Vec<_> = strong.lines().collect();
+    if weak.len() != strong.len() {
+        bail!("块校验和数量不一致: {} != {}", weak.len(), strong.len());
+    }
+    weak.iter()
+        .zip(strong)
+        .map(|(weak, strong)| {
+            let mut sums = weak.split_whitespace().map(str::parse::<u32>);
+            let (Some(Ok(a)), Some(Ok(b))) = (sums.next(), sums.next()) else {
+                bail!("无法解析弱校验和: {}", weak);
+            };
+            let strong = strong.split_whitespace().next().unwrap_or_default();
+            Ok(BlockSignature {
+                weak: combine(a, b),
+                strong: strong.to_owned(),
+            })
+        })
+        .collect()
+}
+
+///计算本地文件相对远程文件的差异, 匹配的块用 Copy 表示, 其他数据用 Literal 表示
+pub(crate) fn compute_delta(
+    local: &[u8],
+    signatures: &[BlockSignature],
+    block_size: usize,
+) -> Delta {
+    let mut blocks: HashMap<u32, Vec<usize>> = HashMap::new();
+    signatures
+        .iter()
+        .enumerate()
+        .for_each(|(i, s)| blocks.entry(s.weak).or_default().push(i));
+
+    let mut delta = Delta::default();
+    let mut literal_start = 0;
+    let mut pos = 0;
+    let mut window: Option<(u32, u32)> = None;
+    while pos + block_size <= local.len() && !blocks.is_empty() {
+        let (a, b) = *window.get_or_insert_with(|| weak_checksum(&local[pos..pos + block_size]));
+        let matched = blocks.get(&combine(a, b)).and_then(|candidates| {
+            let strong = format!("{:x}", Sha256::digest(&local[pos..pos + block_size]));
+            //相同内容的块有多个时优先选择紧接上一个复制的块, 便于合并
+            let next = match (literal_start == pos, delta.ops.last()) {
+                (true, Some(Op::Copy { block, count })) => Some(block + count),
+                _ => None,
+            };
+            let matches: Vec<usize> = candidates
+                .iter()
+                .copied()
+                .filter(|&i| signatures[i].strong == strong)
+                .collect();
+            matches
+                .iter()
+                .copied()
+                .find(|&i| Some(i) == next)
+                .or(matches.first().copied())
+        });
+        match matched {
+            Some(block) => {
+                delta.push_literal(&local[literal_start..pos]);
+                delta.push_copy(block);
+                pos += block_size;
+                literal_start = pos;
+                window = None;
+            }
+            None => {
+                //窗口向后滚动一个字节
+                let out = local[pos] as u32;
+                let a = a.wrapping_sub(out);
+                let b = b.wrapping_sub((block_size as u32).wrapping_mul(out));
+                window = local.get(pos + block_size).map(|&x| {
+                    let a = a.wrapping_add(x as u32);
+                    (a & 0xffff, b.wrapping_add(a) & 0xffff)
+                });
+                pos += 1;
+            }
+        }
+    }
+    delta.push_literal(&local[literal_start..]);
+    delta
+}
+
+impl Delta {
+    fn push_literal(&mut self, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        match self.ops.last_mut() {
+            Some(Op::Literal { len, .. }) => *len += data.len(),
+            _ => self.ops.push(Op::Literal {
+                offset: self.data.len(),
+                len: data.len(),
+            }),
+        }
+        self.data.extend_from_slice(data);
+    }
+
+    fn push_copy(&mut self, block: usize) {
+        match self.ops.last_mut() {
+            Some(Op::Copy {
+                block: start,
+                count,
+            }) if *start + *count == block => *count += 1,
+            _ => self.ops.push(Op::Copy { block, count: 1 }),
+        }
+    }
+
+    ///在远程服务器上用原文件 old 和上传的差异数据 data 重建文件 to 的脚本
+    pub(crate) fn script(&self, old: &Path, data: &Path, to: &Path, block_size: usize) -> String {
+        let mut script = String::from("set -e\n{\n");
+        for op in &self.ops {
+            let line = match *op {
+                Op::Copy { block, count } => ShellCommand::new("dd")
+                    .args([
+                        format!("if={}", old.display()),
+                        format!("bs={}", block_size),
+                        format!("skip={}", block),
+                        format!("count={}", count),
+                    ])
+                    .raw("2>/dev/null"),
+                Op::Literal { offset, len } => ShellCommand::new("tail")
+                    .args(["-c".to_owned(), format!("+{}", offset + 1), "--".to_owned()])
+                    .arg(data)
+                    .raw("| head -c")
+                    .arg(len.to_string()),
+            };
+            script.push_str(line.as_str());
+            script.push('\n');
+        }
+        script.push_str(ShellCommand::default().raw("} >").arg(to).as_str());
+        script.push('\n');
+        script
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::delta::{combine, compute_delta, weak_checksum, BlockSignature, Delta, Op};
+    use sha2::{Digest, Sha256};
+
+    fn signatures(data: &[u8], block_size: usize) -> Vec<BlockSignature> {
+        data.chunks_exact(block_size)
+            .map(|block| {
+                let (a, b) = weak_checksum(block);
+                BlockSignature {
+                    weak: combine(a, b),
+                    strong: format!("{:x}", Sha256::digest(block)),
+                }
+            })
+            .collect()
+    }
+
+    fn apply(old: &[u8], delta: &Delta, block_size: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        for op in &delta.ops {
+            match *op {
+                Op::Copy { block, count } => {
+                    out.extend_from_slice(&old[block * block_size..(block + count) * block_size])
+                }
+                Op::Literal { offset, len } => {
+                    out.extend_from_slice(&delta.data[offset..offset + len])
+                }
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn delta_reconstructs_shifted_file() {
+        let block_size = 64;
+        let old: Vec<u8> = (0..4096u32).map(|i| (i * 7 + i / 13) as u8).collect();
+        let mut new = old.clone();
+        new.splice(100..100, *b"inserted bytes shift the rest");
+        new[3000] ^= 0xff;
+        new.extend_from_slice(b"tail");
+
+        let delta = compute_delta(&new, &signatures(&old, block_size), block_size);
+        assert_eq!(apply(&old, &delta, block_size), new);
+        //只有插入和修改附近的块需要上传
+        assert!(delta.data.len() < 4 * block_size);
+
+        let delta = compute_delta(&old, &signatures(&old, block_size), block_size);
+        assert_eq!(
+            delta.ops,
+            [Op::Copy {
+                block: 0,
+                count: 64
+            }]
+        );
+    }
+}
@@ -0,0 +1,580 @@
+use crate::config::{Hook, Hooks, OnFailure};
+use crate::executable::Executable;
+use crate::report::{HookReport, Plan, Report};
+use crate::target::{SessionPool, Target};
+use crate::Result;
+use anyhow::bail;
+use console::style;
+use std::collections::HashMap;
+use std::future::Future;
+use std::process::Command;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::Semaphore;
+
+fn run_exe(executable: &Executable, hooks: &Hooks, report: &mut Report) -> Result<()> {
+    executable.check_remote_platform()?;
+    executable.preflight_check()?;
+    let (uploaded_bytes, transfer_note) = executable.push_executable_to_remote_server()?;
+    report.uploaded_bytes = Some(uploaded_bytes);
+    report.transfer_note = transfer_note;
+    run_hooks("before_stop", &hooks.before_stop, report, |hook| {
+        executable.run_remote_hook(hook)
+    })?;
+    executable.killall_remote_server_process()?;
+    executable.delete_remote_server_file_and_rename()?;
+    report.swapped = true;
+    executable.start_remote_server_process()?;
+    Ok(())
+}
+
+///依次执行 hook 并记录结果, on_failure 为 abort 的命令失败时返回错误
+fn run_hooks(
+    stage: &'static str,
+    hooks: &[Hook],
+    report: &mut Report,
+    run: impl Fn(&Hook) -> Result<String>,
+) -> Result<()> {
+    for hook in hooks {
+        let result = run(hook);
+        report.hooks.push(HookReport {
+            stage,
+            command: hook.command.clone(),
+            success: result.is_ok(),
+            output: match &result {
+                Ok(output) => output.clone(),
+                Err(e) => format!("{:#}", e),
+            },
+        });
+        if let (Err(e), OnFailure::Abort) = (result, hook.on_failure) {
+            return Err(e.context(format!("{} hook 失败: {}", stage, hook.command)));
+        }
+    }
+    Ok(())
+}
+
+///before_upload hook 的执行结果, key 为 (配置文件, id)
+#[derive(Default)]
+pub(crate) struct BeforeUpload(HashMap<(String, String), (Vec<HookReport>, Option<String>)>);
+
+///在部署到各个主机之前执行 before_upload hook, 同一个可执行文件部署到多个主机时只执行一次
+pub(crate) fn run_before_upload(targets: &[Target]) -> BeforeUpload {
+    let mut results = HashMap::new();
+    for target in targets {
+        let key = (target.config.clone(), target.id.clone());
+        if results.contains_key(&key) {
+            continue;
+        }
+        let Ok(executable) = target.executable_config() else {
+            continue;
+        };
+        let mut report = Report::default();
+        let result = run_hooks(
+            "before_upload",
+            &executable.hooks.before_upload,
+            &mut report,
+            |hook| run_local_command(&hook.command),
+        );
+        let error = result.err().map(|e| format!("{:#}", e));
+        results.insert(key, (report.hooks, error));
+    }
+    BeforeUpload(results)
+}
+
+///在本地执行命令, 返回标准输出和标准错误
+fn run_local_command(command: &str) -> Result<String> {
+    let output = Command::new("sh").arg("-c").arg(command).output()?;
+    let mut result = String::from_utf8_lossy(&output.stdout).into_owned();
+    result.push_str(&String::from_utf8_lossy(&output.stderr));
+    if !output.status.success() {
+        bail!("执行命令失败: {}\n{}", output.status, result.trim_end());
+    }
+    Ok(result)
+}
+
+///部署并收集部署结果, before_upload hook 失败时不部署
+pub(crate) fn deploy(target: &Target, pool: &SessionPool, before_upload: &BeforeUpload) -> Report {
+    let mut report = Report::new(&target.config, &target.id, &target.host);
+    let Some(executable) = report.record(target.connect(pool)) else {
+        return report;
+    };
+    let Some(executable_config) = report.record(target.executable_config()) else {
+        return report;
+    };
+    let key = (target.config.clone(), target.id.clone());
+    if let Some((hooks, error)) = before_upload.0.get(&key) {
+        report.hooks.extend(hooks.iter().cloned());
+        if let Some(error) = error {
+            report.errors.push(error.clone());
+            inspect_executable(&executable, &mut report, None);
+            return report;
+        }
+    }
+    let hooks = &executable_config.hooks;
+    let start = Instant::now();
+    let deployed = run_exe(&executable, hooks, &mut report)
+        .and_then(|()| {
+            executable.check_remote_server_process_health(&executable_config.health_check)
+        })
+        .and_then(|()| {
+            run_hooks("after_start", &hooks.after_start, &mut report, |hook| {
+                executable.run_remote_hook(hook)
+            })
+        });
+    if let Some(()) = report.record(deployed) {
+        report.deploy_duration_ms = Some(start.elapsed().as_millis());
+    }
+    inspect_executable(&executable, &mut report, None);
+    report
+}
+
+///生成部署计划, 只读取远程状态
+pub(crate) fn plan(target: &Target, pool: &SessionPool) -> Report {
+    let mut report = Report::new(&target.config, &target.id, &target.host);
+    let Some(executable) = report.record(target.connect(pool)) else {
+        return report;
+    };
+    let Some(executable_config) = report.record(target.executable_config()) else {
+        return report;
+    };
+    inspect_executable(&executable, &mut report, Some(0));
+    report.record(executable.check_remote_platform());
+    let Some((upload_size, local_sha256)) = report.record(executable.local_executable_sha256())
+    else {
+        return report;
+    };
+    let steps = executable.deploy_steps(
+        upload_size,
+        report.running == Some(true),
+        &executable_config.health_check,
+        &executable_config.hooks,
+    );
+    let disk = report.record(executable.remote_disk());
+    if let Some(disk) = &disk {
+        report.record(executable.upload_size().and_then(|size| disk.check(size)));
+    }
+    report.plan = Some(Plan {
+        upload_size,
+        local_sha256,
+        remote_sha256: report
+            .record(executable.remote_executable_sha256())
+            .flatten(),
+        free_disk_kb: disk.as_ref().and_then(|d| d.free_kb),
+        steps: report.record(steps).unwrap_or_default(),
+        diffs: report
+            .record(executable.template_diffs())
+            .unwrap_or_default(),
+    });
+    report
+}
+
+///回滚到上一个版本
+fn rollback(target: &Target, pool: &SessionPool) -> Report {
+    let mut report = Report::new(&target.config, &target.id, &target.host);
+    let Some(executable) = report.record(target.connect(pool)) else {
+        return report;
+    };
+    report.rolled_back = report
+        .record(executable.rollback_remote_server_process())
+        .is_some();
+    inspect_executable(&executable, &mut report, Some(0));
+    report
+}
+
+///查看运行状态, log_lines 为 None 时读取全部日志, 为 Some(0) 时不读取日志
+pub(crate) fn inspect(target: &Target, pool: &SessionPool, log_lines: Option<u32>) -> Report {
+    let mut report = Report::new(&target.config, &target.id, &target.host);
+    if let Some(executable) = report.record(target.connect(pool)) {
+        inspect_executable(&executable, &mut report, log_lines);
+    }
+    report
+}
+
+fn inspect_executable(x: &Executable, report: &mut Report, log_lines: Option<u32>) {
+    report.running = report.record(x.check_remote_server_process_is_running());
+    report.pid = report
+        .record(x.remote_server_process_pid())
+        .unwrap_or_default();
+    report.log = match log_lines {
+        None => report.record(x.show_remote_server_process_log()),
+        Some(0) => None,
+        Some(n) => report.record(x.show_remote_server_process_log_last(n)),
+    };
+}
+
+///滚动部署每批的大小, 数量或者百分比
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BatchSize {
+    Count(usize),
+    Percent(usize),
+}
+
+impl BatchSize {
+    ///每批的数量, 至少为 1
+    fn size(self, total: usize) -> usize {
+        let size = match self {
+            BatchSize::Count(n) => n,
+            BatchSize::Percent(p) => total * p / 100,
+        };
+        size.max(1)
+    }
+}
+
+impl FromStr for BatchSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let batch_size = match s.strip_suffix('%') {
+            Some(p) => match p.parse() {
+                Ok(p @ 1..=100) => BatchSize::Percent(p),
+                _ => return Err(format!("invalid percentage: {}", s)),
+            },
+            None => match s.parse() {
+                Ok(n @ 1..) => BatchSize::Count(n),
+                _ => return Err(format!("invalid batch size: {}", s)),
+            },
+        };
+        Ok(batch_size)
+    }
+}
+
+///滚动部署, 按批次部署并等待健康检查,
+///失败数超过 max_failures 时停止后续批次, rollback 为 true 时回滚当前批次
+pub(crate) async fn rolling_deploy(
+    targets: Vec<Target>,
+    batch_size: BatchSize,
+    max_failures: usize,
+    rollback_batch: bool,
+    parallel: usize,
+) -> Vec<Report> {
+    let pool = Arc::new(SessionPool::default());
+    let before_upload = Arc::new(run_before_upload(&targets));
+    let size = batch_size.size(targets.len());
+    let batches = targets.chunks(size).map(|b| b.to_vec()).collect();
+    roll_out(
+        batches,
+        max_failures,
+        rollback_batch,
+        |batch| {
+            let (pool, before_upload) = (pool.clone(), before_upload.clone());
+            async move {
+                run_parallel(batch, &pool, parallel, true, move |t, pool| {
+                    deploy(t, pool, &before_upload)
+                })
+                .await
+            }
+        },
+        |targets| {
+            let pool = pool.clone();
+            async move { run_parallel(targets, &pool, parallel, true, rollback).await }
+        },
+    )
+    .await
+}
+
+///依次用 deploy_batch 部署每个批次, 失败数超过 max_failures 时停止后续批次,
+///rollback_batch 为 true 时用 rollback_targets 回滚当前批次中已经替换了文件的目标
+async fn roll_out<D, DF, R, RF>(
+    batches: Vec<Vec<Target>>,
+    max_failures: usize,
+    rollback_batch: bool,
+    mut deploy_batch: D,
+    mut rollback_targets: R,
+) -> Vec<Report>
+where
+    D: FnMut(Vec<Target>) -> DF,
+    DF: Future<Output = Vec<Report>>,
+    R: FnMut(Vec<Target>) -> RF,
+    RF: Future<Output = Vec<Report>>,
+{
+    let batch_count = batches.len();
+    let mut reports = Vec::new();
+    let mut failures = 0;
+    let mut batches = batches.into_iter().enumerate();
+    for (i, batch) in batches.by_ref() {
+        eprintln!("{} {}/{}", style("batch").yellow(), i + 1, batch_count);
+        let mut batch_reports = deploy_batch(batch.clone()).await;
+        failures += batch_reports.iter().filter(|r| r.is_failed()).count();
+
+        if failures > max_failures {
+            eprintln!(
+                "{} {} failed, rollout halted",
+                style("error").red(),
+                failures
+            );
+            if rollback_batch {
+                //替换前失败的主机仍在运行当前版本, 回滚会换成更早的版本
+                let (swapped, targets): (Vec<_>, Vec<_>) = batch_reports
+                    .iter_mut()
+                    .zip(batch)
+                    .filter(|(report, _)| report.swapped)
+                    .unzip();
+                let rollback_reports = rollback_targets(targets).await;
+                swapped
+                    .into_iter()
+                    .zip(rollback_reports)
+                    .for_each(|(report, rollback_report)| {
+                        report.rolled_back = rollback_report.rolled_back;
+                        report.running = rollback_report.running;
+                        report.pid = rollback_report.pid;
+                        report.errors.extend(
+                            rollback_report
+                                .errors
+                                .into_iter()
+                                .map(|e| format!("回滚失败: {}", e)),
+                        );
+                    });
+            }
+            reports.extend(batch_reports);
+            break;
+        }
+        reports.extend(batch_reports);
+    }
+
+    //停止后剩余的批次不再部署
+    batches.flat_map(|(_, batch)| batch).for_each(|t| {
+        let mut report = Report::new(&t.config, &t.id, &t.host);
+        report.errors.push("滚动部署已停止, 未部署".to_owned());
+        reports.push(report);
+    });
+    reports
+}
+
+///并发执行任务, 最多同时执行 parallel 个, 结果保持 targets 的顺序
+pub(crate) async fn run_parallel<F>(
+    targets: Vec<Target>,
+    pool: &Arc<SessionPool>,
+    parallel: usize,
+    progress: bool,
+    f: F,
+) -> Vec<Report>
+where
+    F: Fn(&Target, &SessionPool) -> Report + Send + Sync + 'static,
+{
+    let total = targets.len();
+    let semaphore = Arc::new(Semaphore::new(parallel.max(1)));
+    let finished = Arc::new(AtomicUsize::new(0));
+    let f = Arc::new(f);
+
+    let handles: Vec<_> = targets
+        .into_iter()
+        .map(|target| {
+            let report = Report::new(&target.config, &target.id, &target.host);
+            let (pool, semaphore, finished, f) =
+                (pool.clone(), semaphore.clone(), finished.clone(), f.clone());
+            let handle = tokio::spawn(async move {
+                let _permit = semaphore.acquire_owned().await?;
+                let task = tokio::task::spawn_blocking(move || {
+                    let name = format!("{}/{}@{}", target.config, target.id, target.host);
+                    if progress {
+                        eprintln!("{} {}", style("start").cyan(), name);
+                    }
+                    let start = Instant::now();
+                    let report = f(&target, &pool);
+                    if progress {
+                        let finished = finished.fetch_add(1, Ordering::SeqCst) + 1;
+                        let result = if report.is_failed() {
+                            style("failed").red()
+                        } else {
+                            style("ok").green()
+                        };
+                        eprintln!(
+                            "[{}/{}] {} {} in {:.1}s",
+                            finished,
+                            total,
+                            name,
+                            result,
+                            start.elapsed().as_secs_f64()
+                        );
+                    }
+                    report
+                });
+                Ok::<_, anyhow::Error>(task.await?)
+            });
+            (report, handle)
+        })
+        .collect();
+
+    let mut reports = Vec::with_capacity(total);
+    for (mut report, handle) in handles {
+        match handle.await {
+            Ok(Ok(r)) => report = r,
+            Ok(Err(e)) => report.errors.push(format!("{:#}", e)),
+            Err(e) => report.errors.push(e.to_string()),
+        }
+        reports.push(report);
+    }
+    reports
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::{Hook, OnFailure};
+    use crate::deploy::{roll_out, run_before_upload, run_hooks, run_local_command, BatchSize};
+    use crate::report::Report;
+    use crate::ssh::{ExecOutput, ShellCommand};
+    use crate::target::Target;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn hooks_abort_or_continue() {
+        let hook = |command: &str, on_failure| Hook {
+            command: command.to_owned(),
+            on_failure,
+            timeout_secs: None,
+        };
+        let mut report = Report::default();
+        let hooks = [
+            hook("echo hi", OnFailure::Abort),
+            hook("echo oops >&2; exit 3", OnFailure::Continue),
+            hook("false", OnFailure::Abort),
+            hook("echo never", OnFailure::Abort),
+        ];
+        let result = run_hooks("before_upload", &hooks, &mut report, |hook| {
+            run_local_command(&hook.command)
+        });
+        assert!(result.is_err());
+        assert_eq!(report.hooks.len(), 3);
+        assert_eq!(report.hooks[0].output, "hi\n");
+        assert!(!report.hooks[1].success);
+        assert!(report.hooks[1].output.contains("oops"));
+        assert!(!report.hooks[2].success);
+    }
+
+    #[test]
+    fn failed_remote_hook_keeps_output() {
+        let hook = Hook {
+            command: "echo migrating; echo 'no such table' >&2; exit 2".to_owned(),
+            on_failure: OnFailure::Abort,
+            timeout_secs: None,
+        };
+        let mut report = Report::default();
+        let result = run_hooks("before_stop", &[hook], &mut report, |hook| {
+            //和远程 hook 一样用 2>&1 合并输出, 在本地执行
+            let command = ShellCommand::new("sh")
+                .arg("-c")
+                .arg(&hook.command)
+                .raw("2>&1");
+            let output = std::process::Command::new("sh")
+                .arg("-c")
+                .arg(command.as_str())
+                .output()?;
+            ExecOutput {
+                stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+                stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+                exit_code: output.status.code().unwrap_or(-1),
+                ..Default::default()
+            }
+            .check(&command)
+            .map(|output| output.stdout)
+        });
+        assert!(result.is_err());
+        assert!(!report.hooks[0].success);
+        assert!(report.hooks[0].output.contains("(退出码 2"));
+        assert!(report.hooks[0].output.ends_with("migrating\nno such table"));
+    }
+
+    #[test]
+    fn before_upload_runs_once_per_executable() {
+        let dir = TempDir::new("before-upload");
+        let file = dir.join("built");
+        let executable = format!(
+            "{{id: api, executable_file_name: x, local_path: ., remote_path: /x, hooks: {{before_upload: [{{command: 'echo built >> {}'}}]}}}}",
+            file.display()
+        );
+        let target = |host: &str| {
+            let ssh_account = format!("{{addr: '{}', username: root}}", host);
+            Target::new(
+                "c",
+                serde_yaml::from_str(&ssh_account).unwrap(),
+                serde_yaml::from_str(&executable).unwrap(),
+            )
+        };
+        let before_upload = run_before_upload(&[target("a:22"), target("b:22")]);
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "built\n");
+        let (hooks, error) = &before_upload.0[&("c".to_owned(), "api".to_owned())];
+        assert_eq!(hooks.len(), 1);
+        assert!(error.is_none());
+    }
+
+    #[test]
+    fn batch_size() {
+        assert_eq!("3".parse(), Ok(BatchSize::Count(3)));
+        assert_eq!("25%".parse(), Ok(BatchSize::Percent(25)));
+        assert!("0".parse::<BatchSize>().is_err());
+        assert!("120%".parse::<BatchSize>().is_err());
+        assert_eq!(BatchSize::Percent(25).size(10), 2);
+        assert_eq!(BatchSize::Percent(1).size(10), 1);
+        assert_eq!(BatchSize::Count(4).size(10), 4);
+    }
+
+    #[tokio::test]
+    async fn rollout_halts_and_rolls_back_swapped_targets() {
+        let target = |host: &str| {
+            Target::new(
+                "c",
+                serde_yaml::from_str(&format!("{{addr: '{}', username: root}}", host)).unwrap(),
+                serde_yaml::from_str(
+                    "{id: api, executable_file_name: x, local_path: ., remote_path: /x}",
+                )
+                .unwrap(),
+            )
+        };
+        let batches = vec![
+            vec![target("a:22")],
+            vec![target("b:22"), target("c:22")],
+            vec![target("d:22")],
+        ];
+        //b 替换后健康检查失败, c 在替换前失败
+        let deploy_batch = |batch: Vec<Target>| async move {
+            batch
+                .iter()
+                .map(|t| {
+                    let mut report = Report::new(&t.config, &t.id, &t.host);
+                    report.swapped = t.host != "c:22";
+                    match t.host.as_str() {
+                        "a:22" => report.running = Some(true),
+                        _ => report.errors.push("failed".to_owned()),
+                    }
+                    report
+                })
+                .collect()
+        };
+        let rolled_back = std::sync::Mutex::new(Vec::new());
+        let rollback_targets = |targets: Vec<Target>| {
+            rolled_back
+                .lock()
+                .unwrap()
+                .extend(targets.iter().map(|t| t.host.clone()));
+            async move {
+                targets
+                    .iter()
+                    .map(|t| {
+                        let mut report = Report::new(&t.config, &t.id, &t.host);
+                        report.rolled_back = true;
+                        report
+                    })
+                    .collect()
+            }
+        };
+
+        let reports = roll_out(batches.clone(), 1, true, deploy_batch, rollback_targets).await;
+        assert_eq!(*rolled_back.lock().unwrap(), ["b:22"]);
+        let hosts: Vec<_> = reports.iter().map(|r| r.host.as_str()).collect();
+        assert_eq!(hosts, ["a:22", "b:22", "c:22", "d:22"]);
+        assert!(!reports[0].is_failed());
+        assert!(reports[1].rolled_back);
+        assert!(!reports[2].rolled_back);
+        assert_eq!(reports[3].errors, ["滚动部署已停止, 未部署"]);
+
+        //没有超过阈值时部署所有批次, 不回滚
+        rolled_back.lock().unwrap().clear();
+        let reports = roll_out(batches, 3, true, deploy_batch, rollback_targets).await;
+        assert!(rolled_back.lock().unwrap().is_empty());
+        assert_eq!(reports.len(), 4);
+        assert!(reports[3].is_failed());
+        assert_ne!(reports[3].errors, ["滚动部署已停止, 未部署"]);
+    }
+}
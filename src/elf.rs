@@ -0,0 +1,211 @@
+use crate::Result;
+use anyhow::bail;
+use std::fmt;
+
+///可执行文件链接的 C 库
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Libc {
+    ///静态链接, 没有动态链接器
+    Static,
+    Glibc,
+    Musl,
+    ///无法识别的动态链接器
+    Unknown,
+}
+
+impl fmt::Display for Libc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Libc::Static => "static",
+            Libc::Glibc => "glibc",
+            Libc::Musl => "musl",
+            Libc::Unknown => "unknown",
+        };
+        f.write_str(name)
+    }
+}
+
+///从 ELF 头读取的平台信息
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Platform {
+    ///与 uname -m 一致的架构名称, 例如 x86_64, aarch64
+    pub(crate) arch: String,
+    pub(crate) libc: Libc,
+    ///PT_INTERP 中的动态链接器路径, 静态链接时为 None
+    pub(crate) interp: Option<String>,
+}
+
+const PT_INTERP: u32 = 3;
+
+///解析 ELF 头, 不是 ELF 文件或架构无法识别时返回 None
+pub(crate) fn parse(data: &[u8]) -> Option<Platform> {
+    if data.get(..4)? != b"\x7fELF" {
+        return None;
+    }
+    let is_64 = match data.get(4)? {
+        1 => false,
+        2 => true,
+        _ => return None,
+    };
+    let little_endian = match data.get(5)? {
+        1 => true,
+        2 => false,
+        _ => return None,
+    };
+    let read = |offset: usize, size: usize| -> Option<u64> {
+        let bytes = data.get(offset..offset + size)?;
+        let fold = |v: u64, b: &u8| v << 8 | *b as u64;
+        Some(match little_endian {
+            true => bytes.iter().rev().fold(0, fold),
+            false => bytes.iter().fold(0, fold),
+        })
+    };
+
+    let arch = match read(18, 2)? {
+        3 => "i686",
+        62 => "x86_64",
+        40 => "arm",
+        183 => "aarch64",
+        243 if is_64 => "riscv64",
+        8 => "mips",
+        21 => "ppc64",
+        22 => "s390x",
+        _ => return None,
+    };
+
+    //查找 PT_INTERP 段中的动态链接器
+    let (phoff, phentsize, phnum) = match is_64 {
+        true => (read(32, 8)?, read(54, 2)?, read(56, 2)?),
+        false => (read(28, 4)?, read(42, 2)?, read(44, 2)?),
+    };
+    let mut libc = Libc::Static;
+    let mut interp_path = None;
+    for i in 0..phnum {
+        let ph = (phoff + i * phentsize) as usize;
+        if read(ph, 4)? != PT_INTERP as u64 {
+            continue;
+        }
+        let (offset, size) = match is_64 {
+            true => (read(ph + 8, 8)?, read(ph + 32, 8)?),
+            false => (read(ph + 4, 4)?, read(ph + 16, 4)?),
+        };
+        let interp = data.get(offset as usize..(offset + size) as usize)?;
+        let interp = String::from_utf8_lossy(interp)
+            .trim_end_matches('\0')
+            .to_owned();
+        libc = if interp.contains("musl") {
+            Libc::Musl
+        } else if interp.contains("ld-linux") || interp.contains("ld64.so") {
+            Libc::Glibc
+        } else {
+            Libc::Unknown
+        };
+        interp_path = Some(interp);
+    }
+    Some(Platform {
+        arch: arch.to_owned(),
+        libc,
+        interp: interp_path,
+    })
+}
+
+///把 uname -m 的输出转换为 parse 使用的架构名称
+pub(crate) fn normalize_arch(uname: &str) -> String {
+    let arch = match uname {
+        "i386" | "i486" | "i586" | "i686" => "i686",
+        "amd64" => "x86_64",
+        "arm64" | "aarch64_be" => "aarch64",
+        arch if arch.starts_with("arm") => "arm",
+        "mips64" | "mipsel" => "mips",
+        "ppc64le" => "ppc64",
+        arch => arch,
+    };
+    arch.to_owned()
+}
+
+///检查本地可执行文件能否在远程服务器上运行,
+///remote_arch 为远程服务器的架构, has_interp 为远程服务器上是否存在可执行文件需要的动态链接器
+pub(crate) fn check_compatible(
+    local: &Platform,
+    remote_arch: &str,
+    has_interp: bool,
+) -> Result<()> {
+    //64 位的 x86_64 和 aarch64 通常也能运行对应的 32 位程序
+    let arch_ok = local.arch == remote_arch
+        || (local.arch == "i686" && remote_arch == "x86_64")
+        || (local.arch == "arm" && remote_arch == "aarch64");
+    if !arch_ok {
+        bail!(
+            "架构不匹配: 本地可执行文件为 {}, 远程服务器为 {}",
+            local.arch,
+            remote_arch
+        );
+    }
+    if let (Some(interp), false) = (&local.interp, has_interp) {
+        bail!(
+            "C 库不匹配: 本地可执行文件链接 {}, 远程服务器上没有动态链接器 {}",
+            local.libc,
+            interp
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::elf::{check_compatible, normalize_arch, parse, Libc};
+
+    ///构造只有一个 PT_INTERP 段的 64 位小端 ELF 文件
+    fn elf64(machine: u16, interp: Option<&str>) -> Vec<u8> {
+        let mut data = vec![0u8; 64 + 56];
+        data[..6].copy_from_slice(b"\x7fELF\x02\x01");
+        data[18..20].copy_from_slice(&machine.to_le_bytes());
+        data[32..40].copy_from_slice(&64u64.to_le_bytes());
+        data[54..56].copy_from_slice(&56u16.to_le_bytes());
+        data[56..58].copy_from_slice(&1u16.to_le_bytes());
+        if let Some(interp) = interp {
+            data[64..68].copy_from_slice(&3u32.to_le_bytes());
+            let offset = data.len() as u64;
+            data[72..80].copy_from_slice(&offset.to_le_bytes());
+            data[96..104].copy_from_slice(&(interp.len() as u64).to_le_bytes());
+            data.extend_from_slice(interp.as_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn parse_elf_header() {
+        let glibc = parse(&elf64(62, Some("/lib64/ld-linux-x86-64.so.2"))).unwrap();
+        assert_eq!(glibc.arch, "x86_64");
+        assert_eq!(glibc.libc, Libc::Glibc);
+        let musl = parse(&elf64(183, Some("/lib/ld-musl-aarch64.so.1"))).unwrap();
+        assert_eq!(musl.arch, "aarch64");
+        assert_eq!(musl.libc, Libc::Musl);
+        assert_eq!(parse(&elf64(183, None)).unwrap().libc, Libc::Static);
+        assert_eq!(parse(b"#!/bin/sh\n"), None);
+    }
+
+    #[test]
+    fn compatible_platforms() {
+        let platform = |arch: &str, interp: Option<&str>| {
+            parse(&elf64(
+                match arch {
+                    "x86_64" => 62,
+                    "arm" => 40,
+                    _ => 183,
+                },
+                interp,
+            ))
+            .unwrap()
+        };
+        let remote = normalize_arch("arm64");
+        let musl = Some("/lib/ld-musl-aarch64.so.1");
+        let glibc = Some("/lib/ld-linux-aarch64.so.1");
+        assert_eq!(platform("aarch64", musl).interp.as_deref(), musl);
+        assert!(check_compatible(&platform("aarch64", None), &remote, false).is_ok());
+        assert!(check_compatible(&platform("aarch64", musl), &remote, false).is_err());
+        //远程服务器同时安装了 musl 时只看需要的动态链接器是否存在
+        assert!(check_compatible(&platform("aarch64", glibc), &remote, true).is_ok());
+        assert!(check_compatible(&platform("x86_64", glibc), &remote, true).is_err());
+    }
+}
@@ -1,128 +1,721 @@
-use crate::ssh::SSHClient;
+use crate::config::{CompanionFile, HealthCheck, Hook, Hooks, Overwrite, Transfer};
+use crate::delta;
+use crate::elf;
+use crate::ssh::{SSHClient, ShellCommand};
 use crate::Result;
-use anyhow::bail;
+use anyhow::{anyhow, bail, Context};
 use bytes::Bytes;
+use sha2::{Digest, Sha256};
+use similar::TextDiff;
+use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
-use std::rc::Rc;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
 #[derive(Clone)]
 pub(crate) struct Executable {
-    client: Rc<SSHClient>,
+    client: Arc<SSHClient>,
     ///可执行文件的名称
     name: String,
     ///远程可执行文件的路径
     remote_path: PathBuf,
     ///本地可执行文件的名称
     local_path: PathBuf,
+    ///启动参数
+    args: Vec<String>,
+    ///启动时设置的环境变量
+    env: BTreeMap<String, String>,
+    ///和可执行文件一起部署的文件
+    files: Vec<CompanionFile>,
+    ///上传可执行文件的方式
+    transfer: Transfer,
 }
 
 impl Executable {
     pub(crate) fn new(
-        client: Rc<SSHClient>,
+        client: Arc<SSHClient>,
         name: String,
         remote_path: impl AsRef<Path>,
         local_path: impl AsRef<Path>,
-    ) -> Self {
+    ) -> Result<Self> {
         let remote_path = remote_path.as_ref().to_path_buf();
         let local_path = local_path.as_ref().to_path_buf();
         if name.is_empty()
-            || remote_path.to_string_lossy().is_empty()
-            || local_path.to_string_lossy().is_empty()
+            || remote_path.as_os_str().is_empty()
+            || local_path.as_os_str().is_empty()
         {
-            panic!("name or remote_path or local_path is empty");
+            bail!("executable_file_name, remote_path 和 local_path 不能为空");
         }
-        Self {
+        Ok(Self {
             client,
             name,
             remote_path,
             local_path,
-        }
+            args: Vec::new(),
+            env: BTreeMap::new(),
+            files: Vec::new(),
+            transfer: Transfer::default(),
+        })
+    }
+
+    ///设置启动参数和环境变量
+    pub(crate) fn with_args(mut self, args: Vec<String>, env: BTreeMap<String, String>) -> Self {
+        self.args = args;
+        self.env = env;
+        self
+    }
+
+    ///设置和可执行文件一起部署的文件
+    pub(crate) fn with_files(mut self, files: Vec<CompanionFile>) -> Self {
+        self.files = files;
+        self
+    }
+
+    ///设置上传可执行文件的方式
+    pub(crate) fn with_transfer(mut self, transfer: Transfer) -> Self {
+        self.transfer = transfer;
+        self
     }
 
-    ///推送可执行文件到远程服务器
-    pub(crate) fn push_executable_to_remote_server(&self) -> Result<()> {
-        let from = self.local_path.join(self.name.as_str());
-        let to = self
-            .remote_path
-            .join(format!("{}.temp", self.name).as_str());
+    ///推送可执行文件到远程服务器, 返回上传可执行文件实际发送的字节数
+    pub(crate) fn push_executable_to_remote_server(&self) -> Result<(u64, Option<String>)> {
         if !self.client.dir_exists(self.remote_path.as_path())? {
             self.client
                 .sftp()?
                 .mkdir(self.remote_path.as_path(), 0o777)?;
         }
-        self.client
-            .upload(to.as_path(), Bytes::from(std::fs::read(from)?), 0o777)?;
-        Ok(())
+        let data = std::fs::read(self.local_file())?;
+        //差异传输失败时上传整个文件, 并记录原因
+        let (sent, note) = match self.transfer.delta.then(|| self.push_delta(&data)) {
+            Some(Ok(sent)) => (sent, None),
+            delta => {
+                let note = delta
+                    .and_then(|r| r.err())
+                    .map(|e| format!("差异传输失败, 已上传整个文件: {:#}", e));
+                let sent = data.len() as u64;
+                self.client
+                    .upload(self.temp_file().as_path(), Bytes::from(data), 0o777)?;
+                (sent, note)
+            }
+        };
+        for file in self.files_to_upload()? {
+            if let Some(parent) = file.remote.parent() {
+                self.client
+                    .exec(&ShellCommand::new("mkdir").arg("-p").arg("--").arg(parent))?;
+            }
+            let contents = match &file.rendered {
+                Some(rendered) => rendered.as_bytes().to_vec(),
+                None => std::fs::read(&file.local)
+                    .with_context(|| format!("读取 {} 失败", file.local.display()))?,
+            };
+            self.client
+                .upload(with_suffix(&file.remote, "temp"), contents, file.mode)?;
+        }
+        Ok((sent, note))
+    }
+
+    ///只上传和远程可执行文件不同的块, 在远程服务器上重建为 temp 文件, 返回发送的字节数
+    fn push_delta(&self, data: &[u8]) -> Result<u64> {
+        let remote = self.remote_file();
+        if data.len() < delta::MIN_FILE_SIZE {
+            bail!("文件小于 {}KB", delta::MIN_FILE_SIZE / 1024);
+        }
+        if self.client.file_exists(&remote)?.is_none() {
+            bail!("远程文件 {} 不存在", remote.display());
+        }
+        let block_size = delta::block_size(data.len());
+        let output = self
+            .client
+            .exec(&delta::signature_command(&remote, block_size))?
+            .stdout;
+        let signatures = delta::parse_signatures(&output)?;
+        let delta = delta::compute_delta(data, &signatures, block_size);
+        if delta.data.len() * 2 > data.len() {
+            bail!("差异太大, 需要上传 {} 字节", delta.data.len());
+        }
+
+        let data_file = with_suffix(&remote, "delta");
+        let script_file = with_suffix(&remote, "delta.sh");
+        let temp = self.temp_file();
+        let script = delta.script(&remote, &data_file, &temp, block_size);
+        let sent = (delta.data.len() + script.len()) as u64;
+        self.client.upload(&data_file, delta.data, 0o644)?;
+        self.client.upload(&script_file, script, 0o644)?;
+        let result = self.client.exec(
+            &ShellCommand::new("sh")
+                .arg(&script_file)
+                .raw("&& chmod 777 --")
+                .arg(&temp)
+                .raw("&& sha256sum --")
+                .arg(&temp),
+        );
+        let _ = self.client.exec(
+            &ShellCommand::new("rm")
+                .arg("-f")
+                .arg("--")
+                .arg(&data_file)
+                .arg(&script_file),
+        );
+        let expected = format!("{:x}", Sha256::digest(data));
+        if result?.stdout.split_whitespace().next() != Some(expected.as_str()) {
+            bail!("差异传输后 {} 的 sha256 不一致", temp.display());
+        }
+        Ok(sent)
+    }
+
+    ///需要上传的文件, overwrite 为 never 且远程已存在的文件不上传
+    fn files_to_upload(&self) -> Result<Vec<&CompanionFile>> {
+        let mut files = Vec::new();
+        for file in &self.files {
+            if file.overwrite == Overwrite::Always
+                || self.client.file_exists(&file.remote)?.is_none()
+            {
+                files.push(file);
+            }
+        }
+        Ok(files)
     }
 
     ///kill远程服务器上的进程
     pub(crate) fn killall_remote_server_process(&self) -> Result<()> {
         if self.check_remote_server_process_is_running()? {
-            self.client
-                .exec(format!("killall -9 {}", self.name).as_str())?;
+            self.client.exec(&self.kill_command())?;
         }
         Ok(())
     }
 
-    ///删除远程服务器上的文件并修改上传文件的文件名
+    fn kill_command(&self) -> ShellCommand {
+        ShellCommand::new("killall")
+            .arg("-9")
+            .arg("--")
+            .arg(&self.name)
+    }
+
+    ///删除远程服务器上的文件并修改上传文件的文件名, 原文件保留为 filename.bak 用于回滚
     /// from: filename.tmp
     /// to: filename
     pub(crate) fn delete_remote_server_file_and_rename(&self) -> Result<()> {
-        let from = self
-            .remote_path
-            .join(format!("{}.temp", self.name).as_str());
-        let to = self.remote_path.join(self.name.as_str());
+        let from = self.temp_file();
+        let to = self.remote_file();
         if from.is_dir() || to.is_dir() {
             bail!("from: {} or to: {} is dir", from.display(), to.display());
         }
+        for command in self.swap_commands() {
+            self.client.exec(&command)?;
+        }
+        Ok(())
+    }
+
+    ///替换文件的命令, 依次执行, 上传的其他文件同样保留 .bak 用于回滚
+    fn swap_commands(&self) -> Vec<ShellCommand> {
+        let from = self.temp_file();
+        let to = self.remote_file();
+        let bak = self.remote_path.join(format!("{}.bak", self.name).as_str());
+        let mut commands = vec![
+            ShellCommand::new("rm").arg("-rf").arg("--").arg(&bak),
+            ShellCommand::default()
+                .raw("if [ -e")
+                .arg(&to)
+                .raw("]; then mv --")
+                .args([&to, &bak])
+                .raw("; fi"),
+            ShellCommand::new("mv").arg("--").args([&from, &to]),
+        ];
+        commands.extend(self.files.iter().map(|f| {
+            let temp = with_suffix(&f.remote, "temp");
+            let bak = with_suffix(&f.remote, "bak");
+            ShellCommand::default()
+                .raw("if [ -e")
+                .arg(&temp)
+                .raw("]; then rm -rf --")
+                .arg(&bak)
+                .raw("; if [ -e")
+                .arg(&f.remote)
+                .raw("]; then mv --")
+                .args([&f.remote, &bak])
+                .raw("; fi; mv --")
+                .args([&temp, &f.remote])
+                .raw("; fi")
+        }));
+        commands
+    }
+
+    ///本地可执行文件
+    fn local_file(&self) -> PathBuf {
+        self.local_path.join(self.name.as_str())
+    }
+
+    ///远程可执行文件
+    fn remote_file(&self) -> PathBuf {
+        self.remote_path.join(self.name.as_str())
+    }
+
+    ///进程的日志文件
+    fn log_file(&self) -> PathBuf {
+        with_suffix(&self.remote_file(), "log")
+    }
+
+    ///上传时使用的临时文件
+    fn temp_file(&self) -> PathBuf {
+        self.remote_path
+            .join(format!("{}.temp", self.name).as_str())
+    }
+
+    ///回滚到上一次部署的版本并重新启动
+    /// from: filename.bak
+    /// to: filename
+    pub(crate) fn rollback_remote_server_process(&self) -> Result<()> {
+        let from = self.remote_path.join(format!("{}.bak", self.name).as_str());
+        let to = self.remote_path.join(self.name.as_str());
+        if self.client.file_exists(from.as_path())?.is_none() {
+            bail!("没有可回滚的版本: {}", from.display());
+        }
+        self.killall_remote_server_process()?;
         self.client
-            .exec(format!("rm -rf {}", to.display()).as_str())?;
+            .exec(&ShellCommand::new("rm").arg("-rf").arg("--").arg(&to))?;
         self.client
-            .exec(format!("mv {} {}", from.display(), to.display()).as_str())?;
-        Ok(())
+            .exec(&ShellCommand::new("mv").arg("--").args([&from, &to]))?;
+        for file in &self.files {
+            let bak = with_suffix(&file.remote, "bak");
+            self.client.exec(
+                &ShellCommand::default()
+                    .raw("if [ -e")
+                    .arg(&bak)
+                    .raw("]; then rm -rf --")
+                    .arg(&file.remote)
+                    .raw("; mv --")
+                    .args([&bak, &file.remote])
+                    .raw("; fi"),
+            )?;
+        }
+        self.start_remote_server_process()
     }
 
     ///启动远程服务器上的进程
     pub(crate) fn start_remote_server_process(&self) -> Result<()> {
-        //nohup /mnt/usb/disk1/picture/aml-picture > /mnt/usb/disk1/picture/aml-picture.log 2>&1 &
-        let cmd = format!(
-            "nohup {0} > {0}.log 2>&1 &",
-            self.remote_path.join(self.name.as_str()).display()
-        );
-        self.client.exec(cmd.as_str())?;
+        self.client.exec(&self.start_command())?;
         Ok(())
     }
 
+    fn start_command(&self) -> ShellCommand {
+        //nohup /mnt/usb/disk1/picture/aml-picture > /mnt/usb/disk1/picture/aml-picture.log 2>&1 &
+        //环境变量通过 env 设置, 变量名和值都作为参数加上引号
+        let command = match self.env.is_empty() {
+            true => ShellCommand::default(),
+            false => {
+                ShellCommand::new("env").args(self.env.iter().map(|(k, v)| format!("{}={}", k, v)))
+            }
+        };
+        command
+            .raw("nohup")
+            .arg(self.remote_file())
+            .args(&self.args)
+            .raw(">")
+            .arg(self.log_file())
+            .raw("2>&1 &")
+    }
+
     ///查看程序是否在运行
     pub(crate) fn check_remote_server_process_is_running(&self) -> Result<bool> {
         let result = self
             .client
-            .exec(format!("ps -ef | grep {0} | grep -v grep | wc -l", self.name).as_str())?;
+            .exec(
+                &ShellCommand::new("ps")
+                    .arg("-ef")
+                    .raw("| grep -e")
+                    .arg(&self.name)
+                    .raw("| grep -v grep | wc -l"),
+            )?
+            .stdout;
         let is_running = result.trim() == "1";
 
         Ok(is_running)
     }
+    ///健康检查, 失败时按配置重试
+    pub(crate) fn check_remote_server_process_health(
+        &self,
+        health_check: &HealthCheck,
+    ) -> Result<()> {
+        thread::sleep(Duration::from_secs(health_check.delay_secs));
+        let mut attempt = 0;
+        loop {
+            let result = match &health_check.command {
+                Some(command) => self
+                    .client
+                    .exec(&self.health_check_command(command))
+                    .map(|_| ()),
+                None => match self.check_remote_server_process_is_running() {
+                    Ok(true) => Ok(()),
+                    Ok(false) => Err(anyhow!("{} 没有运行", self.name)),
+                    Err(e) => Err(e),
+                },
+            };
+            if result.is_ok() || attempt >= health_check.retries {
+                return result.context("健康检查失败");
+            }
+            attempt += 1;
+            thread::sleep(Duration::from_secs(health_check.interval_secs));
+        }
+    }
+
+    ///用户配置的命令作为 sh -c 的参数执行
+    fn health_check_command(&self, command: &str) -> ShellCommand {
+        ShellCommand::new("cd")
+            .arg(&self.remote_path)
+            .raw("&& sh -c")
+            .arg(command)
+    }
+
+    ///在远程服务器的 remote_path 中执行 hook, 返回标准输出和标准错误
+    pub(crate) fn run_remote_hook(&self, hook: &Hook) -> Result<String> {
+        self.client
+            .exec_with_timeout(&self.hook_command(&hook.command), hook.timeout())
+            .map(|output| output.stdout)
+    }
+
+    fn hook_command(&self, command: &str) -> ShellCommand {
+        ShellCommand::new("cd")
+            .arg(&self.remote_path)
+            .raw("&& sh -c")
+            .arg(command)
+            .raw("2>&1")
+    }
+
+    ///检查本地可执行文件的架构和 C 库能否在远程服务器上运行, 不是 ELF 文件时不检查
+    pub(crate) fn check_remote_platform(&self) -> Result<()> {
+        let local_file = self.local_file();
+        let data = std::fs::read(&local_file)
+            .with_context(|| format!("读取 {} 失败", local_file.display()))?;
+        let Some(local) = elf::parse(&data) else {
+            return Ok(());
+        };
+        let arch = self
+            .client
+            .exec(&ShellCommand::new("uname").arg("-m"))?
+            .stdout;
+        //直接检查需要的动态链接器, 远程服务器可能同时安装了 glibc 和 musl
+        let has_interp = match &local.interp {
+            Some(interp) => self
+                .client
+                .exec(&ShellCommand::new("test").arg("-e").arg(interp))
+                .is_ok(),
+            None => true,
+        };
+        elf::check_compatible(&local, &elf::normalize_arch(arch.trim()), has_interp)
+    }
+
+    ///渲染后的模板和远程文件的差异, 没有变化的模板不返回
+    pub(crate) fn template_diffs(&self) -> Result<BTreeMap<String, String>> {
+        let mut diffs = BTreeMap::new();
+        for file in &self.files {
+            let Some(rendered) = &file.rendered else {
+                continue;
+            };
+            let remote = match self.client.file_exists(&file.remote)? {
+                Some(_) => {
+                    String::from_utf8_lossy(&self.client.download(&file.remote)?).into_owned()
+                }
+                None => String::new(),
+            };
+            if &remote == rendered {
+                continue;
+            }
+            let remote_name = file.remote.display().to_string();
+            let diff = TextDiff::from_lines(&remote, rendered)
+                .unified_diff()
+                .header(&remote_name, &file.local.display().to_string())
+                .to_string();
+            diffs.insert(remote_name, diff);
+        }
+        Ok(diffs)
+    }
+
+    ///本地可执行文件的大小和 sha256
+    pub(crate) fn local_executable_sha256(&self) -> Result<(u64, String)> {
+        let local_file = self.local_file();
+        let data = std::fs::read(&local_file)
+            .with_context(|| format!("读取 {} 失败", local_file.display()))?;
+        Ok((data.len() as u64, format!("{:x}", Sha256::digest(&data))))
+    }
+
+    ///远程可执行文件的 sha256, 文件不存在时为 None
+    pub(crate) fn remote_executable_sha256(&self) -> Result<Option<String>> {
+        let remote_file = self.remote_file();
+        let result = self
+            .client
+            .exec(
+                &ShellCommand::default()
+                    .raw("if [ -e")
+                    .arg(&remote_file)
+                    .raw("]; then sha256sum --")
+                    .arg(&remote_file)
+                    .raw("; fi"),
+            )?
+            .stdout;
+        Ok(result.split_whitespace().next().map(str::to_owned))
+    }
+
+    ///远程目录所在文件系统的状态, 目录不存在时使用最近的上级目录
+    pub(crate) fn remote_disk(&self) -> Result<RemoteDisk> {
+        let result = self
+            .client
+            .exec(&ShellCommand::default().var("d", &self.remote_path).raw(
+                "while [ ! -e \"$d\" ]; do d=$(dirname \"$d\"); done; \
+                 df -Pk \"$d\" | tail -n 1; \
+                 if [ -w \"$d\" ]; then echo writable; else echo readonly; fi; \
+                 m=$(df -Pk \"$d\" | tail -n 1 | awk '{print $NF}'); \
+                 awk -v m=\"$m\" '$2 == m {o = $4} END {print o}' /proc/mounts 2>/dev/null; true",
+            ))?
+            .stdout;
+        Ok(RemoteDisk::parse(&result))
+    }
+
+    ///上传前检查远程目录所在文件系统的可用空间, 是否可写以及是否以 noexec 挂载
+    pub(crate) fn preflight_check(&self) -> Result<()> {
+        self.remote_disk()?.check(self.upload_size()?)
+    }
+
+    ///需要上传的可执行文件和其他文件的总大小
+    pub(crate) fn upload_size(&self) -> Result<u64> {
+        let mut size = std::fs::metadata(self.local_file())
+            .with_context(|| format!("读取 {} 失败", self.local_file().display()))?
+            .len();
+        for file in self.files_to_upload()? {
+            size += match &file.rendered {
+                Some(rendered) => rendered.len() as u64,
+                None => std::fs::metadata(&file.local)
+                    .with_context(|| format!("读取 {} 失败", file.local.display()))?
+                    .len(),
+            };
+        }
+        Ok(size)
+    }
+
+    ///部署会执行的步骤, 只读取远程状态, 不做修改
+    pub(crate) fn deploy_steps(
+        &self,
+        local_size: u64,
+        running: bool,
+        health_check: &HealthCheck,
+        hooks: &Hooks,
+    ) -> Result<Vec<String>> {
+        let mut steps = Vec::new();
+        hooks
+            .before_upload
+            .iter()
+            .for_each(|h| steps.push(format!("local: {}", h.command)));
+        if !self.client.dir_exists(self.remote_path.as_path())? {
+            steps.push(format!("mkdir {}", self.remote_path.display()));
+        }
+        steps.push(format!(
+            "upload {} -> {} ({} bytes{})",
+            self.local_file().display(),
+            self.temp_file().display(),
+            local_size,
+            match self.transfer.delta {
+                true => ", delta",
+                false => "",
+            }
+        ));
+        for file in self.files_to_upload()? {
+            steps.push(format!(
+                "upload {} -> {} (mode {:o})",
+                file.local.display(),
+                with_suffix(&file.remote, "temp").display(),
+                file.mode
+            ));
+        }
+        hooks
+            .before_stop
+            .iter()
+            .for_each(|h| steps.push(self.hook_command(&h.command).to_string()));
+        match running {
+            true => steps.push(self.kill_command().to_string()),
+            false => steps.push(format!("# {} 没有运行, 不需要停止", self.name)),
+        }
+        steps.extend(self.swap_commands().iter().map(ToString::to_string));
+        steps.push(self.start_command().to_string());
+        if health_check.delay_secs > 0 {
+            steps.push(format!("sleep {}", health_check.delay_secs));
+        }
+        steps.push(match &health_check.command {
+            Some(command) => format!("health check: {}", self.health_check_command(command)),
+            None => format!("health check: {} is running", self.name),
+        });
+        hooks
+            .after_start
+            .iter()
+            .for_each(|h| steps.push(self.hook_command(&h.command).to_string()));
+        Ok(steps)
+    }
+
+    ///查看程序的进程号
+    pub(crate) fn remote_server_process_pid(&self) -> Result<Vec<u32>> {
+        let result = self
+            .client
+            .exec(&ShellCommand::new("pidof").arg(&self.name).raw("|| true"))?
+            .stdout;
+        Ok(result
+            .split_whitespace()
+            .filter_map(|pid| pid.parse().ok())
+            .collect())
+    }
     ///查看程序日志
     pub(crate) fn show_remote_server_process_log(&self) -> Result<String> {
-        let log = self.client.exec(
-            format!(
-                "cat {}.log",
-                self.remote_path.join(self.name.as_str()).display()
-            )
-            .as_str(),
-        )?;
+        let log = self
+            .client
+            .exec(&ShellCommand::new("cat").arg("--").arg(self.log_file()))?
+            .stdout;
         Ok(log)
     }
     ///查看程序日志的最后n行
     pub(crate) fn show_remote_server_process_log_last(&self, n: u32) -> Result<String> {
-        let log = self.client.exec(
-            format!(
-                "tail -n {} {}.log",
-                n,
-                self.remote_path.join(self.name.as_str()).display()
-            )
-            .as_str(),
-        )?;
+        let log = self
+            .client
+            .exec(
+                &ShellCommand::new("tail")
+                    .arg("-n")
+                    .arg(n.to_string())
+                    .arg("--")
+                    .arg(self.log_file()),
+            )?
+            .stdout;
         Ok(log)
     }
+
+    ///日志的字节数, 日志不存在时为 0
+    pub(crate) fn log_size(&self) -> Result<u64> {
+        let output = self
+            .client
+            .exec(
+                &ShellCommand::default()
+                    .var("f", self.log_file())
+                    .raw("if [ -e \"$f\" ]; then wc -c < \"$f\"; else echo 0; fi"),
+            )?
+            .stdout;
+        output
+            .trim()
+            .parse()
+            .with_context(|| format!("无法解析日志大小: {}", output.trim()))
+    }
+
+    ///从 offset 字节开始读取日志, 返回新的 offset, 日志被截断时从头读取
+    pub(crate) fn read_remote_server_process_log_from(&self, offset: u64) -> Result<(u64, String)> {
+        let output = self
+            .client
+            .exec(
+                &ShellCommand::default()
+                    .var("f", self.log_file())
+                    .var("offset", offset.to_string())
+                    .var("start", offset.saturating_add(1).to_string())
+                    .raw(
+                        "[ -e \"$f\" ] || exit 0; size=$(wc -c < \"$f\"); echo $size; \
+                     if [ $size -lt $offset ]; then cat -- \"$f\"; else tail -c +$start -- \"$f\"; fi",
+                    ),
+            )?
+            .stdout;
+        let Some((size, log)) = output.split_once('\n') else {
+            return Ok((offset, String::new()));
+        };
+        let start = match size.trim().parse::<u64>() {
+            Ok(size) if size < offset => 0,
+            _ => offset,
+        };
+        Ok((start + log.len() as u64, log.to_owned()))
+    }
+}
+
+///远程目录所在文件系统的状态
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct RemoteDisk {
+    ///挂载点
+    pub(crate) mount_point: String,
+    ///可用空间(KB)
+    pub(crate) free_kb: Option<u64>,
+    ///当前用户是否有写权限
+    pub(crate) writable: bool,
+    ///挂载选项, 无法读取 /proc/mounts 时为空
+    pub(crate) options: Vec<String>,
+}
+
+impl RemoteDisk {
+    ///解析 df -Pk 的最后一行, writable 或 readonly, /proc/mounts 中的挂载选项
+    fn parse(output: &str) -> Self {
+        let mut lines = output.lines();
+        let df: Vec<_> = lines
+            .next()
+            .unwrap_or_default()
+            .split_whitespace()
+            .collect();
+        Self {
+            mount_point: df.last().copied().unwrap_or_default().to_owned(),
+            free_kb: df.get(3).and_then(|free| free.parse().ok()),
+            writable: lines.next() == Some("writable"),
+            options: lines
+                .next()
+                .unwrap_or_default()
+                .split(',')
+                .filter(|o| !o.is_empty())
+                .map(str::to_owned)
+                .collect(),
+        }
+    }
+
+    ///检查能否上传 size 字节并运行上传的可执行文件
+    pub(crate) fn check(&self, size: u64) -> Result<()> {
+        let has_option = |option: &str| self.options.iter().any(|o| o == option);
+        if !self.writable || has_option("ro") {
+            bail!("远程目录所在的 {} 不可写", self.mount_point);
+        }
+        if has_option("noexec") {
+            bail!(
+                "远程目录所在的 {} 以 noexec 挂载, 无法运行可执行文件",
+                self.mount_point
+            );
+        }
+        if let Some(free_kb) = self.free_kb {
+            if free_kb.saturating_mul(1024) < size {
+                bail!(
+                    "远程磁盘空间不足: {} 可用 {} KB, 需要 {} KB",
+                    self.mount_point,
+                    free_kb,
+                    size.div_ceil(1024)
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+///在文件名后添加后缀, 例如 app.toml -> app.toml.temp
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".");
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::executable::RemoteDisk;
+
+    #[test]
+    fn check_remote_disk() {
+        let disk = RemoteDisk::parse(
+            "/dev/sda1 1000 900 100 90% /mnt/usb\nwritable\nrw,nosuid,relatime\n",
+        );
+        assert_eq!(disk.mount_point, "/mnt/usb");
+        assert_eq!(disk.free_kb, Some(100));
+        assert!(disk.check(100 * 1024).is_ok());
+        assert!(disk.check(100 * 1024 + 1).is_err());
+
+        let noexec = RemoteDisk::parse("tmpfs 1000 0 1000 0% /tmp\nwritable\nrw,noexec\n");
+        assert!(noexec.check(1).is_err());
+        let readonly = RemoteDisk::parse("/dev/sda1 1000 0 1000 0% /\nwritable\nro\n");
+        assert!(readonly.check(1).is_err());
+        //没有 /proc/mounts 时只检查空间和权限
+        let unknown = RemoteDisk::parse("/dev/sda1 1000 0 1000 0% /\nwritable\n\n");
+        assert!(unknown.check(1).is_ok());
+    }
 }
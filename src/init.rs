@@ -0,0 +1,234 @@
+use crate::config::{Config, ConfigFormat, Executable, Inventory, SSHAccount};
+use crate::target::get_ssh_client;
+use crate::Result;
+use anyhow::bail;
+use console::{style, Term};
+use std::env;
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
+
+///交互式生成配置文件
+pub(crate) fn init(config_path: &Path) -> Result<()> {
+    let term = Term::stdout();
+    if config_path.exists()
+        && !confirm(
+            &term,
+            &format!("{} already exists, overwrite it?", config_path.display()),
+            false,
+        )?
+    {
+        bail!("已取消");
+    }
+
+    let ssh_account = loop {
+        let ssh_account = prompt_ssh_account(&term)?;
+        term.write_line("testing ssh connection ...")?;
+        match get_ssh_client(&ssh_account) {
+            Ok(_) => {
+                term.write_line(&format!("{}", style("connected").green()))?;
+                break ssh_account;
+            }
+            Err(e) => {
+                term.write_line(&format!("{} {:#}", style("error:").red(), e))?;
+                if !confirm(&term, "enter the ssh account again?", true)? {
+                    break ssh_account;
+                }
+            }
+        }
+    };
+
+    let local_file = prompt_local_file(&term)?;
+    let executable_file_name = local_file
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned();
+    let local_path = local_file.parent().unwrap_or(Path::new("")).to_path_buf();
+    let id = prompt(&term, "executable id", Some("default"))?;
+    let remote_path = loop {
+        let default_remote_path = format!("/opt/{}/", executable_file_name);
+        let remote_path = prompt(&term, "remote path", Some(&default_remote_path))?;
+        if remote_path.starts_with('/') {
+            break remote_path;
+        }
+        term.write_line("remote path must be absolute")?;
+    };
+
+    let config = Config {
+        extends: Vec::new(),
+        defaults: Default::default(),
+        vars: Default::default(),
+        profiles: Default::default(),
+        ssh_account: Some(ssh_account),
+        inventory_file: None,
+        inventory: Inventory::default(),
+        executable_config_list: vec![Executable {
+            id,
+            executable_file_name,
+            local_path: local_path.into_boxed_path(),
+            remote_path: PathBuf::from(remote_path).into_boxed_path(),
+            tags: Vec::new(),
+            args: Vec::new(),
+            env: Default::default(),
+            health_check: Default::default(),
+            hooks: Default::default(),
+            files: Vec::new(),
+            templates: Vec::new(),
+            build: None,
+            transfer: Default::default(),
+            ssh_account: None,
+            hosts: Vec::new(),
+            groups: Vec::new(),
+        }],
+    };
+    let format = ConfigFormat::from_path(config_path);
+    std::fs::write(config_path, format.to_string(&config)?)?;
+    term.write_line(&format!(
+        "config written to {}",
+        style(config_path.display()).cyan()
+    ))?;
+    Ok(())
+}
+
+fn prompt_ssh_account(term: &Term) -> Result<SSHAccount> {
+    let mut addr = prompt(term, "host (ip:port)", None)?;
+    if !addr.contains(':') {
+        addr.push_str(":22");
+    }
+    let username = prompt(term, "username", Some("root"))?;
+    let auth = loop {
+        let auth = prompt(term, "auth method (password/key)", Some("password"))?;
+        if auth == "password" || auth == "key" {
+            break auth;
+        }
+    };
+
+    let (password, private_key) = if auth == "key" {
+        let default_key = default_private_key();
+        let default_key = default_key.as_ref().map(|k| k.to_string_lossy());
+        let private_key = prompt(term, "private key", default_key.as_deref())?;
+        let passphrase = prompt_secure(term, "passphrase (empty for none)")?;
+        (
+            passphrase,
+            Some(PathBuf::from(private_key).into_boxed_path()),
+        )
+    } else {
+        (prompt_secure(term, "password")?, None)
+    };
+    Ok(SSHAccount {
+        addr,
+        username,
+        password,
+        private_key,
+        vars: Default::default(),
+        compress: false,
+    })
+}
+
+///选择 target/*/release 下编译好的可执行文件, 或者输入路径
+fn prompt_local_file(term: &Term) -> Result<PathBuf> {
+    let binaries = find_release_binaries(Path::new("target"));
+    if !binaries.is_empty() {
+        term.write_line("found executables:")?;
+        for (i, binary) in binaries.iter().enumerate() {
+            term.write_line(&format!("  {}) {}", i + 1, binary.display()))?;
+        }
+    }
+    loop {
+        let input = prompt(term, "local executable (number or path)", None)?;
+        let local_file = match input.parse::<usize>() {
+            Ok(i) if (1..=binaries.len()).contains(&i) => binaries[i - 1].clone(),
+            _ => PathBuf::from(input),
+        };
+        if local_file.is_file() {
+            return Ok(local_file);
+        }
+        term.write_line(&format!("{} is not a file", local_file.display()))?;
+    }
+}
+
+///target/release 和 target/<triple>/release 下的可执行文件
+fn find_release_binaries(target_dir: &Path) -> Vec<PathBuf> {
+    let mut release_dirs = vec![target_dir.join("release")];
+    if let Ok(dir) = std::fs::read_dir(target_dir) {
+        release_dirs.extend(dir.flatten().map(|d| d.path().join("release")));
+    }
+
+    let mut binaries: Vec<_> = release_dirs
+        .iter()
+        .filter_map(|d| std::fs::read_dir(d).ok())
+        .flat_map(|dir| dir.flatten().map(|d| d.path()))
+        .filter(|p| p.is_file() && is_executable(p))
+        .collect();
+    binaries.sort();
+    binaries.dedup();
+    binaries
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.extension().is_none()
+        && path
+            .metadata()
+            .is_ok_and(|m| m.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.extension().is_none_or(|e| e == "exe")
+}
+
+fn default_private_key() -> Option<PathBuf> {
+    let ssh_dir = PathBuf::from(env::var_os("HOME")?).join(".ssh");
+    ["id_ed25519", "id_rsa", "id_ecdsa"]
+        .iter()
+        .map(|name| ssh_dir.join(name))
+        .find(|key| key.is_file())
+}
+
+fn prompt(term: &Term, label: &str, default: Option<&str>) -> Result<String> {
+    loop {
+        match default {
+            Some(default) => term.write_str(&format!("{} [{}]: ", label, default))?,
+            None => term.write_str(&format!("{}: ", label))?,
+        }
+        let input = read_line(term, false)?.trim().to_owned();
+        match (input.is_empty(), default) {
+            (true, Some(default)) => return Ok(default.to_owned()),
+            (true, None) => continue,
+            (false, _) => return Ok(input),
+        }
+    }
+}
+
+fn prompt_secure(term: &Term, label: &str) -> Result<String> {
+    term.write_str(&format!("{}: ", label))?;
+    read_line(term, true)
+}
+
+fn confirm(term: &Term, label: &str, default: bool) -> Result<bool> {
+    let hint = if default { "Y/n" } else { "y/N" };
+    term.write_str(&format!("{} [{}]: ", label, hint))?;
+    let input = read_line(term, false)?.trim().to_lowercase();
+    Ok(match input.as_str() {
+        "y" | "yes" => true,
+        "n" | "no" => false,
+        _ => default,
+    })
+}
+
+///从终端读取一行, 标准输入不是终端时直接读取标准输入
+fn read_line(term: &Term, secure: bool) -> Result<String> {
+    if io::stdin().is_terminal() {
+        return Ok(match secure {
+            true => term.read_secure_line()?,
+            false => term.read_line()?,
+        });
+    }
+    let mut line = String::new();
+    if io::stdin().read_line(&mut line)? == 0 {
+        bail!("输入已结束");
+    }
+    Ok(line.trim_end_matches(['\r', '\n']).to_owned())
+}
@@ -1,309 +1,481 @@
+mod build;
 mod cli;
 mod config;
+mod delta;
+mod deploy;
+mod elf;
 mod executable;
+mod init;
+mod report;
 mod ssh;
-
-use crate::cli::Commands;
-use crate::config::Config;
-use crate::executable::Executable;
-use crate::ssh::SSHClient;
+mod target;
+#[cfg(test)]
+mod test_util;
+mod watch;
+
+use crate::cli::{Commands, ConfigCommands, HostFilter, Selector, Validate};
+use crate::config::{find_line, Config, LoadOptions, SSHAccount, ValidationError};
+use crate::deploy::{deploy, inspect, plan, rolling_deploy, run_before_upload, run_parallel};
+use crate::report::{
+    print_errors, print_hooks, print_plan, print_structured, print_summary, print_value,
+    style_running, OutputFormat, Report,
+};
+use crate::target::{
+    filter_targets, get_all_config_executable, get_all_executable, get_config_files,
+    get_config_path, select_targets, SessionPool, Target,
+};
+use anyhow::bail;
 pub(crate) use anyhow::Result;
 use clap::Parser;
 use cli::Cli;
 use console::style;
-use std::env;
-use std::net::TcpStream;
+use serde::Serialize;
 use std::path::{Path, PathBuf};
-use std::rc::Rc;
-use tokio::fs;
+use std::process::exit;
+use std::sync::Arc;
+use std::thread;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
-    match &cli.command {
+    let output = cli.output;
+    let options = LoadOptions {
+        profile: cli.profile.clone(),
+        vars: cli.vars.clone(),
+    };
+    let pool = Arc::new(SessionPool::default());
+    let (command, reports) = match &cli.command {
         Commands::Run(run) => {
-            if run.all_id == true {
-                let exe_list = get_all_executable(run.config.clone()).await?;
-                exe_list.iter().for_each(|(id, x)| {
-                    run_exe(x).unwrap();
-                    let is_running = x.check_remote_server_process_is_running().unwrap();
-                    let log = x.show_remote_server_process_log().unwrap();
-
-                    let is_running = if is_running {
-                        style(is_running).green()
-                    } else {
-                        style(is_running).red()
-                    };
-
-                    println!("log:\n{}\n", log);
-                    println!("is running: {}\n", style(is_running).cyan());
-                });
+            let targets = get_targets(
+                &run.config,
+                &run.all_config,
+                run.all_id,
+                &run.select,
+                &run.filter,
+                &options,
+            )
+            .await?;
+            //--dry-run 不执行编译, 只在部署计划中列出
+            if run.build && !run.dry_run {
+                build::build(&targets)?;
+            }
+            let progress = targets.len() > 1;
+            let reports = if run.dry_run {
+                let with_build = run.build;
+                let reports = run_parallel(targets, &pool, run.parallel, false, move |t, pool| {
+                    let mut report = plan(t, pool);
+                    if let (true, Some(plan), Some(step)) =
+                        (with_build, report.plan.as_mut(), build::build_step(t))
+                    {
+                        plan.steps.insert(0, step);
+                    }
+                    report
+                })
+                .await;
+                if output == OutputFormat::Text {
+                    reports.iter().for_each(|r| {
+                        if progress {
+                            println!("id: {} host: {}", style(&r.id).cyan(), r.host);
+                        }
+                        print_errors(r);
+                        println!("is running: {}", style_running(r.running == Some(true)));
+                        if let Some(plan) = &r.plan {
+                            print_plan(plan);
+                        }
+                        println!();
+                    });
+                }
+                reports
             } else {
-                let x = get_executable(run.config.clone(), run.id.clone()).await?;
-                run_exe(&x).unwrap();
-                let is_running = x.check_remote_server_process_is_running().unwrap();
-                let log = x.show_remote_server_process_log().unwrap();
-
-                let is_running = if is_running {
-                    style(is_running).green()
-                } else {
-                    style(is_running).red()
+                let reports = match run.batch_size {
+                    Some(batch_size) => {
+                        rolling_deploy(
+                            targets,
+                            batch_size,
+                            run.max_failures,
+                            run.rollback,
+                            run.parallel,
+                        )
+                        .await
+                    }
+                    None => {
+                        //before_upload hook 在所有主机部署之前执行一次
+                        let before_upload = Arc::new(run_before_upload(&targets));
+                        run_parallel(targets, &pool, run.parallel, progress, move |t, pool| {
+                            deploy(t, pool, &before_upload)
+                        })
+                        .await
+                    }
                 };
 
-                println!("log:\n{}\n", log);
-                println!("is running: {}\n", style(is_running).cyan());
-            }
+                if output == OutputFormat::Text {
+                    reports.iter().for_each(|r| {
+                        if progress {
+                            println!("id: {}", style(&r.id).cyan());
+                        }
+                        print_hooks(r);
+                        if let Some(bytes) = r.uploaded_bytes {
+                            println!("uploaded: {} bytes", bytes);
+                        }
+                        if let Some(note) = &r.transfer_note {
+                            println!("{}", style(note).yellow());
+                        }
+                        print_errors(r);
+                        println!("log:\n{}\n", r.log.as_deref().unwrap_or_default());
+                        println!("is running: {}\n", style_running(r.running == Some(true)));
+                    });
+                }
+                reports
+            };
+            ("run", reports)
         }
         Commands::Log(log) => {
-            if log.all_id {
-                let exe_list = get_all_executable(log.config.clone()).await?;
-
-                let mut running_list = Vec::new();
-
-                exe_list.iter().for_each(|(id, x)| {
-                    println!("id: {}", style(id).cyan());
-                    let is_running = x.check_remote_server_process_is_running().unwrap();
-
-                    running_list.push((id, is_running));
-
-                    let is_running = if is_running {
-                        style(is_running).green()
-                    } else {
-                        style(is_running).red()
-                    };
-                    println!(
-                        "log: last {} line\n{}",
-                        10,
-                        x.show_remote_server_process_log_last(10).unwrap()
-                    );
-                    println!("is running: {}\n", is_running);
-                });
-                println!("state list:");
-                running_list.iter().for_each(|(id, is_running)| {
-                    let is_running = if *is_running {
-                        style(is_running).green()
-                    } else {
-                        style(is_running).red()
-                    };
-                    println!("id: {} is running: {}", style(id).cyan(), is_running);
-                });
-                return Ok(());
-            }
-            if log.all_config.is_some() {
-                let exe_list = get_all_config_executable(log.all_config.clone().unwrap()).await?;
-                let mut all_no_running = Vec::new();
-                let mut all_task_count = 0;
-                let mut all_no_running_task_count = 0;
-
-                exe_list.iter().for_each(|e| {
-                    println!("\nconfig: {}", style(e.0.as_str()).yellow());
-                    let mut no_running = Vec::new();
-
-                    e.1.iter().for_each(|(id, x)| {
-                        all_task_count += 1;
-                        let is_running = x.check_remote_server_process_is_running().unwrap();
-                        let is_running = if is_running {
-                            style(is_running).green()
-                        } else {
-                            all_no_running_task_count += 1;
-                            no_running.push(id);
-                            style(is_running).red()
-                        };
-                        println!("id: {} is running: {}", style(id).cyan(), is_running);
+            let targets = get_targets(
+                &log.config,
+                &log.all_config,
+                log.all_id,
+                &log.select,
+                &log.filter,
+                &options,
+            )
+            .await?;
+            let reports = if log.all_id {
+                let reports = run_parallel(targets, &pool, log.parallel, false, |t, pool| {
+                    inspect(t, pool, Some(10))
+                })
+                .await;
+
+                if output == OutputFormat::Text {
+                    reports.iter().for_each(|r| {
+                        println!("id: {}", style(&r.id).cyan());
+                        print_errors(r);
+                        println!(
+                            "log: last {} line\n{}",
+                            10,
+                            r.log.as_deref().unwrap_or_default()
+                        );
+                        println!("is running: {}\n", style_running(r.running == Some(true)));
                     });
-
-                    if no_running.len() == 0 {
-                        println!("There are a {} of ten tasks, all of which are running.", style(e.1.len()).cyan());
-                    } else {
-                        println!("There are a total of {} tasks, of which {} are not running.", style(e.1.len()).cyan(), style(no_running.len()).red());
-                        print!("id: ");
-                        no_running.iter().for_each(|id| {
-                            print!("{} ", style(id).red());
-                        });
-                        println!("are not running.");
-                        all_no_running.push((e.0.as_str().to_owned(), no_running));
-                    }
-                    println!("---------------------");
-                });
-
-                if all_no_running.len() == 0 {
-                    println!("There are a {} of ten tasks, all of which are running.", style(all_task_count).cyan());
-                }else {
-                    println!("There are a total of {} tasks, of which {} are not running.", style(all_task_count).cyan(), style(all_no_running_task_count).red());
-                    all_no_running.iter().for_each(|(config_name, no_running_id)| {
-                        println!("config: {}", style(config_name).yellow());
-                        print!("id: ");
-                        no_running_id.iter().for_each(|id| {
-                            print!("{} ", style(id).red());
-                        });
-                        println!("\n");
+                    print_state_list(&reports);
+                }
+                reports
+            } else if log.all_config.is_some() {
+                let reports = run_parallel(targets, &pool, log.parallel, false, |t, pool| {
+                    inspect(t, pool, Some(0))
+                })
+                .await;
+                if output == OutputFormat::Text {
+                    print_config_summary(&reports);
+                }
+                reports
+            } else {
+                let multiple_ids = targets.iter().any(|t| t.id != targets[0].id);
+                let multiple_hosts = targets.iter().any(|t| t.host != targets[0].host);
+                let reports = run_parallel(targets, &pool, log.parallel, false, |t, pool| {
+                    inspect(t, pool, None)
+                })
+                .await;
+
+                if output == OutputFormat::Text {
+                    reports.iter().for_each(|r| {
+                        if multiple_ids {
+                            println!("id: {}", style(&r.id).cyan());
+                        }
+                        if multiple_hosts {
+                            println!("host: {}", style(&r.host).cyan());
+                        }
+                        print_errors(r);
+                        println!("log:\n{}\n", r.log.as_deref().unwrap_or_default());
+                        println!("is running: {}\n", style_running(r.running == Some(true)));
                     });
                 }
-                return Ok(());
-            }
-            let x = get_executable(log.config.clone(), log.id.clone()).await?;
-            let is_running = x.check_remote_server_process_is_running()?;
-            let log = x.show_remote_server_process_log()?;
-
-            let is_running = if is_running {
-                style(is_running).green()
+                reports
+            };
+            ("log", reports)
+        }
+        Commands::Status(status) => {
+            let targets = get_targets(
+                &status.config,
+                &status.all_config,
+                status.all_id,
+                &status.select,
+                &status.filter,
+                &options,
+            )
+            .await?;
+            let reports = if status.all_config.is_some() {
+                let reports = run_parallel(targets, &pool, status.parallel, false, |t, pool| {
+                    inspect(t, pool, Some(0))
+                })
+                .await;
+                if output == OutputFormat::Text {
+                    print_config_summary(&reports);
+                }
+                reports
             } else {
-                style(is_running).red()
+                let reports = run_parallel(targets, &pool, status.parallel, false, |t, pool| {
+                    inspect(t, pool, Some(0))
+                })
+                .await;
+                if output == OutputFormat::Text {
+                    reports.iter().for_each(print_errors);
+                    print_state_list(&reports);
+                }
+                reports
             };
-
-            println!("log:\n{}\n", log);
-            println!("is running: {}\n", is_running);
+            ("status", reports)
+        }
+        Commands::Config(ConfigCommands::Validate(validate)) => {
+            return validate_config(validate, &options, output).await;
+        }
+        Commands::Config(ConfigCommands::Schema) => {
+            //schema 默认输出 json
+            let format = match output {
+                OutputFormat::Text => OutputFormat::Json,
+                format => format,
+            };
+            return print_value(format, &config::schema());
+        }
+        Commands::Init(init) => {
+            return init::init(&get_config_path(init.config.clone())?);
         }
+        Commands::Watch(watch) => {
+            let targets = get_targets(
+                &watch.config,
+                &None,
+                watch.all_id,
+                &watch.select,
+                &watch.filter,
+                &options,
+            )
+            .await?;
+            return watch::watch(targets, watch);
+        }
+    };
+
+    if output == OutputFormat::Text && reports.len() > 1 {
+        println!();
+        print_summary(&reports);
     }
-    Ok(())
-}
+    print_structured(output, command, &reports)?;
 
-fn get_config_and_id(config: Option<Box<Path>>, id: Option<String>) -> Result<(Box<Path>, String)> {
-    let mut default_config = get_executable_dir()?;
-    default_config.push("exe-default-config.yaml");
-    let config = config.unwrap_or(Box::from(default_config.as_path()));
-    let id = id.unwrap_or("default".to_string());
-    Ok((config, id))
+    if reports.iter().any(|r| r.is_failed()) {
+        exit(1);
+    }
+    Ok(())
 }
 
-fn get_executable_dir() -> Result<PathBuf> {
-    Ok(env::current_dir()?)
-}
+///检查配置文件, 有问题时以非零状态退出
+async fn validate_config(
+    validate: &Validate,
+    options: &LoadOptions,
+    output: OutputFormat,
+) -> Result<()> {
+    let config_files = match &validate.all_config {
+        Some(all_config) => get_config_files(all_config).await?,
+        None => vec![get_config_path(validate.config.clone())?.to_path_buf()],
+    };
+
+    let mut problems = Vec::new();
+    for config_file in &config_files {
+        if !config_file.exists() {
+            let message = "配置文件不存在".to_owned();
+            problems.push(ValidationError::new(config_file, None, message));
+            continue;
+        }
+        let (config, mut config_problems) = Config::validate(config_file, options);
+        if let (Some(config), true) = (config, validate.check_hosts) {
+            config_problems.extend(check_hosts(config_file, &config));
+        }
+        if output == OutputFormat::Text {
+            if config_problems.is_empty() {
+                println!("{}: {}", config_file.display(), style("ok").green());
+            }
+            config_problems.iter().for_each(|p| {
+                println!("{}", style(p).red());
+            });
+        }
+        problems.extend(config_problems);
+    }
 
-fn run_exe(executable: &Executable) -> Result<()> {
-    executable.push_executable_to_remote_server()?;
-    executable.killall_remote_server_process()?;
-    executable.delete_remote_server_file_and_rename()?;
-    executable.start_remote_server_process()?;
+    #[derive(Serialize)]
+    struct Output<'a> {
+        command: &'a str,
+        files: &'a [PathBuf],
+        problems: &'a [ValidationError],
+    }
+    print_value(
+        output,
+        &Output {
+            command: "config validate",
+            files: &config_files,
+            problems: &problems,
+        },
+    )?;
+
+    if !problems.is_empty() {
+        if output == OutputFormat::Text {
+            println!("found {} problems", style(problems.len()).red());
+        }
+        exit(1);
+    }
     Ok(())
 }
 
-fn get_ssh_client(host: &str, username: &str, password: &str) -> Result<SSHClient> {
-    let tcp = TcpStream::connect(host).unwrap();
-    let client = SSHClient::new(tcp);
-    client.auth_by_password(username, password);
-    Ok(client)
-}
-
-async fn get_executable(config: Option<Box<Path>>, id: Option<String>) -> Result<Executable> {
-    let config_and_id = get_config_and_id(config, id)?;
-    let config = Config::load(config_and_id.0.as_ref()).await?;
-    let ssh_account = config.ssh_account;
-    let ssh_client = Rc::new(get_ssh_client(
-        &ssh_account.addr,
-        &ssh_account.username,
-        &ssh_account.password,
-    )?);
-    let executable_config = config
+///同时连接配置中的所有主机, 返回无法连接的主机
+fn check_hosts(config_file: &Path, config: &Config) -> Vec<ValidationError> {
+    let source = std::fs::read_to_string(config_file).unwrap_or_default();
+    let mut accounts: Vec<SSHAccount> = Vec::new();
+    config
         .executable_config_list
         .iter()
-        .find(|x| x.id == config_and_id.1)
-        .ok_or(anyhow::anyhow!("not found this id"))?;
-    let executable = Executable::new(
-        ssh_client,
-        executable_config.executable_file_name.clone(),
-        executable_config.remote_path.clone(),
-        executable_config.local_path.clone(),
-    );
-    Ok(executable)
+        .filter_map(|e| config.resolve_hosts(e).ok())
+        .flatten()
+        .for_each(|(_, account)| {
+            if !accounts
+                .iter()
+                .any(|a| a.addr == account.addr && a.username == account.username)
+            {
+                accounts.push(account);
+            }
+        });
+
+    let pool = SessionPool::default();
+    thread::scope(|scope| {
+        let handles: Vec<_> = accounts
+            .iter()
+            .map(|account| {
+                let pool = &pool;
+                scope.spawn(move || (account, pool.get(account)))
+            })
+            .collect();
+        handles
+            .into_iter()
+            .filter_map(|h| match h.join() {
+                Ok((account, Err(e))) => Some(ValidationError::new(
+                    config_file,
+                    find_line(&source, 0, "addr", Some(&account.addr)),
+                    format!("{:#}", e),
+                )),
+                _ => None,
+            })
+            .collect()
+    })
 }
 
-async fn get_all_executable(config: Option<Box<Path>>) -> Result<Vec<(String, Executable)>> {
-    let mut default_config = get_executable_dir()?;
-    default_config.push("exe-default-config.yaml");
-    let config_path = config.unwrap_or(Box::from(default_config.as_path()));
-
-    let config = Config::load(config_path.as_ref()).await?;
-    let ssh_account = config.ssh_account;
-    let ssh_client = Rc::new(get_ssh_client(
-        &ssh_account.addr,
-        &ssh_account.username,
-        &ssh_account.password,
-    )?);
-
-    let executable_list: Vec<_> = config
-        .executable_config_list
-        .into_iter()
-        .map(|e| {
-            (
-                e.id,
-                Executable::new(
-                    ssh_client.clone(),
-                    e.executable_file_name,
-                    e.remote_path,
-                    e.local_path,
-                ),
-            )
-        })
-        .collect();
-
-    Ok(executable_list)
+///按命令行参数获取目标, 并按 id, 标签, 主机和主机组过滤
+async fn get_targets(
+    config: &Option<Box<Path>>,
+    all_config: &Option<Box<Path>>,
+    all_id: bool,
+    select: &Selector,
+    filter: &HostFilter,
+    options: &LoadOptions,
+) -> Result<Vec<Target>> {
+    let targets = if let Some(all_config) = all_config {
+        get_all_config_executable(all_config.clone(), options).await?
+    } else {
+        get_all_executable(config.clone(), options).await?
+    };
+    //没有指定 id 和标签时使用 id 为 default 的可执行文件
+    let select_all = all_id || all_config.is_some();
+    let default_id = ["default".to_owned()];
+    let ids = if select.id.is_empty() && select.tag.is_empty() && !select_all {
+        &default_id[..]
+    } else {
+        &select.id[..]
+    };
+    let targets = select_targets(targets, ids, &select.tag, &select.exclude)?;
+    if targets.is_empty() && !select_all {
+        bail!("not found this id");
+    }
+    Ok(filter_targets(targets, &filter.host, &filter.group))
 }
 
-async fn get_all_config_executable(
-    config_dir: Box<Path>,
-) -> Result<Vec<(String, Vec<(String, Executable)>)>> {
-    let mut config_files = Vec::new();
+fn print_state_list(reports: &[Report]) {
+    println!("state list:");
+    reports.iter().for_each(|r| {
+        println!(
+            "id: {} is running: {}",
+            style(&r.id).cyan(),
+            style_running(r.running == Some(true))
+        );
+    });
+}
 
-    let mut dir = fs::read_dir(config_dir.as_ref()).await?;
-    while let Some(d) = dir.next_entry().await? {
-        let path = d.path();
-        if path.is_file() {
-            let filename = path.file_name().unwrap().to_str().unwrap();
-            if filename.starts_with("exe-") && filename.ends_with("yaml") {
-                config_files.push(path);
+///按配置文件分组输出运行状态
+fn print_config_summary(reports: &[Report]) {
+    let mut all_no_running: Vec<(&str, Vec<&str>)> = Vec::new();
+    let mut all_no_running_task_count = 0;
+
+    reports
+        .chunk_by(|a, b| a.config == b.config)
+        .for_each(|group| {
+            let config_name = group[0].config.as_str();
+            println!("\nconfig: {}", style(config_name).yellow());
+            let mut no_running = Vec::new();
+
+            group.iter().for_each(|r| {
+                print_errors(r);
+                let is_running = r.running == Some(true);
+                if !is_running {
+                    all_no_running_task_count += 1;
+                    no_running.push(r.id.as_str());
+                }
+                println!(
+                    "id: {} is running: {}",
+                    style(&r.id).cyan(),
+                    style_running(is_running)
+                );
+            });
+
+            if no_running.is_empty() {
+                println!(
+                    "There are a {} of ten tasks, all of which are running.",
+                    style(group.len()).cyan()
+                );
+            } else {
+                println!(
+                    "There are a total of {} tasks, of which {} are not running.",
+                    style(group.len()).cyan(),
+                    style(no_running.len()).red()
+                );
+                print!("id: ");
+                no_running.iter().for_each(|id| {
+                    print!("{} ", style(id).red());
+                });
+                println!("are not running.");
+                all_no_running.push((config_name, no_running));
             }
-        }
-    }
-
-    let mut config_and_ssh_client_list = Vec::new();
-
-    for config_file in config_files {
-        let config = Config::load(config_file.as_path()).await?;
-        let ssh_account = &config.ssh_account;
-        let ssh_client = Rc::new(get_ssh_client(
-            &ssh_account.addr,
-            &ssh_account.username,
-            &ssh_account.password,
-        )?);
-        config_and_ssh_client_list.push((config_file, config, ssh_client));
+            println!("---------------------");
+        });
+
+    if all_no_running.is_empty() {
+        println!(
+            "There are a {} of ten tasks, all of which are running.",
+            style(reports.len()).cyan()
+        );
+    } else {
+        println!(
+            "There are a total of {} tasks, of which {} are not running.",
+            style(reports.len()).cyan(),
+            style(all_no_running_task_count).red()
+        );
+        all_no_running
+            .iter()
+            .for_each(|(config_name, no_running_id)| {
+                println!("config: {}", style(config_name).yellow());
+                print!("id: ");
+                no_running_id.iter().for_each(|id| {
+                    print!("{} ", style(id).red());
+                });
+                println!("\n");
+            });
     }
-
-    let executable_list: Vec<_> = config_and_ssh_client_list
-        .into_iter()
-        .map(|(config_filepath, config, ssh_client)| {
-            let config_name = config_filepath
-                .file_stem()
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .to_owned();
-
-            let exe = config
-                .executable_config_list
-                .into_iter()
-                .map(|e| {
-                    let executable = Executable::new(
-                        ssh_client.clone(),
-                        e.executable_file_name,
-                        e.remote_path,
-                        e.local_path,
-                    );
-                    (e.id, executable)
-                })
-                .collect();
-            (config_name, exe)
-        })
-        .collect();
-
-    Ok(executable_list)
 }
 
 #[cfg(test)]
 mod test {
-    use crate::get_all_config_executable;
-    use std::path::Path;
-
     #[tokio::test]
     async fn t() {}
 }
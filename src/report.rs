@@ -0,0 +1,256 @@
+use crate::Result;
+use clap::ValueEnum;
+use console::style;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+///输出格式
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+    Yaml,
+}
+
+///单个可执行文件的执行结果
+#[derive(Debug, Clone, Default, Serialize)]
+pub(crate) struct Report {
+    ///配置文件名称
+    pub(crate) config: String,
+    pub(crate) id: String,
+    ///远程服务器地址
+    pub(crate) host: String,
+    pub(crate) running: Option<bool>,
+    pub(crate) pid: Vec<u32>,
+    ///部署耗时(毫秒)
+    pub(crate) deploy_duration_ms: Option<u128>,
+    ///上传可执行文件实际发送的字节数, 使用差异传输时小于文件大小
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) uploaded_bytes: Option<u64>,
+    ///开启了差异传输但上传了整个文件的原因
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) transfer_note: Option<String>,
+    ///是否已经用上传的文件替换了远程文件, 只有替换过的才需要回滚
+    pub(crate) swapped: bool,
+    ///滚动部署失败后是否已回滚到上一个版本
+    pub(crate) rolled_back: bool,
+    pub(crate) errors: Vec<String>,
+    pub(crate) log: Option<String>,
+    ///执行过的 hook
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) hooks: Vec<HookReport>,
+    ///--dry-run 时的部署计划
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) plan: Option<Plan>,
+}
+
+///一个 hook 的执行结果
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct HookReport {
+    ///before_upload, before_stop 或 after_start
+    pub(crate) stage: &'static str,
+    pub(crate) command: String,
+    pub(crate) success: bool,
+    ///命令的输出, 失败时为错误信息
+    pub(crate) output: String,
+}
+
+///部署计划, 只读取远程状态, 不做任何修改
+#[derive(Debug, Clone, Default, Serialize)]
+pub(crate) struct Plan {
+    ///本地可执行文件的大小(字节)
+    pub(crate) upload_size: u64,
+    pub(crate) local_sha256: String,
+    ///远程当前可执行文件的 sha256, 文件不存在时为 None
+    pub(crate) remote_sha256: Option<String>,
+    ///远程目录所在磁盘的可用空间(KB)
+    pub(crate) free_disk_kb: Option<u64>,
+    ///按顺序执行的步骤
+    pub(crate) steps: Vec<String>,
+    ///远程文件 -> 模板渲染结果与远程文件的差异
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) diffs: BTreeMap<String, String>,
+}
+
+impl Report {
+    pub(crate) fn new(config: &str, id: &str, host: &str) -> Self {
+        Self {
+            config: config.to_owned(),
+            id: id.to_owned(),
+            host: host.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    ///有错误或进程未运行即视为失败, 部署计划只看是否有错误
+    pub(crate) fn is_failed(&self) -> bool {
+        !self.errors.is_empty() || (self.plan.is_none() && self.running != Some(true))
+    }
+
+    ///记录一个步骤的结果, 出错时保存错误信息
+    pub(crate) fn record<T>(&mut self, result: Result<T>) -> Option<T> {
+        match result {
+            Ok(v) => Some(v),
+            Err(e) => {
+                self.errors.push(format!("{:#}", e));
+                None
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Summary {
+    total: usize,
+    succeeded: usize,
+    failed: usize,
+}
+
+impl Summary {
+    fn new(reports: &[Report]) -> Self {
+        let failed = reports.iter().filter(|r| r.is_failed()).count();
+        Self {
+            total: reports.len(),
+            succeeded: reports.len() - failed,
+            failed,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Output<'a> {
+    command: &'a str,
+    results: &'a [Report],
+    summary: Summary,
+}
+
+///按指定格式输出结构化结果, text 格式由调用方自行输出
+pub(crate) fn print_structured(
+    format: OutputFormat,
+    command: &str,
+    reports: &[Report],
+) -> Result<()> {
+    let output = Output {
+        command,
+        results: reports,
+        summary: Summary::new(reports),
+    };
+    print_value(format, &output)
+}
+
+///按指定格式输出任意结构化数据, text 格式不输出
+pub(crate) fn print_value(format: OutputFormat, value: &impl Serialize) -> Result<()> {
+    match format {
+        OutputFormat::Text => {}
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(value)?),
+        OutputFormat::Yaml => print!("{}", serde_yaml::to_string(value)?),
+    }
+    Ok(())
+}
+
+pub(crate) fn style_running(is_running: bool) -> console::StyledObject<bool> {
+    if is_running {
+        style(is_running).green()
+    } else {
+        style(is_running).red()
+    }
+}
+
+pub(crate) fn print_errors(report: &Report) {
+    report.errors.iter().for_each(|e| {
+        println!("error: {}", style(e).red());
+    });
+}
+
+pub(crate) fn print_hooks(report: &Report) {
+    report.hooks.iter().for_each(|h| {
+        let result = match h.success {
+            true => style("ok").green(),
+            false => style("failed").red(),
+        };
+        println!("hook {} `{}` {}", h.stage, h.command, result);
+        if !h.output.is_empty() {
+            println!("{}", h.output.trim_end());
+        }
+    });
+}
+
+pub(crate) fn print_plan(plan: &Plan) {
+    let remote_sha256 = plan.remote_sha256.as_deref().unwrap_or("-");
+    let changed = match plan.remote_sha256.as_deref() == Some(plan.local_sha256.as_str()) {
+        true => style("unchanged").green(),
+        false => style("changed").yellow(),
+    };
+    println!("local sha256:  {}", plan.local_sha256);
+    println!("remote sha256: {} ({})", remote_sha256, changed);
+    match plan.free_disk_kb {
+        Some(free) => println!("free disk: {} KB", free),
+        None => println!("free disk: -"),
+    }
+    println!("plan:");
+    plan.steps.iter().enumerate().for_each(|(i, step)| {
+        println!("  {}. {}", i + 1, step);
+    });
+    plan.diffs.values().for_each(|diff| {
+        diff.lines().for_each(|line| {
+            let line = match line.chars().next() {
+                Some('+') => style(line).green(),
+                Some('-') => style(line).red(),
+                _ => style(line),
+            };
+            println!("{}", line);
+        });
+    });
+}
+
+///输出汇总表
+pub(crate) fn print_summary(reports: &[Report]) {
+    let width = |f: fn(&Report) -> &str, title: &str| {
+        reports
+            .iter()
+            .map(|r| f(r).chars().count())
+            .chain([title.len()])
+            .max()
+            .unwrap_or_default()
+    };
+    let config_width = width(|r| &r.config, "CONFIG");
+    let id_width = width(|r| &r.id, "ID");
+    let host_width = width(|r| &r.host, "HOST");
+
+    println!("summary:");
+    println!(
+        "{:<config_width$}  {:<id_width$}  {:<host_width$}  {:<7}  RESULT",
+        "CONFIG", "ID", "HOST", "RUNNING"
+    );
+    reports.iter().for_each(|r| {
+        let running = r.running.map(|b| b.to_string()).unwrap_or("-".to_owned());
+        let running = style(format!("{:<7}", running));
+        let running = if r.running == Some(true) {
+            running.green()
+        } else {
+            running.red()
+        };
+        let result = if r.is_failed() {
+            style("failed").red()
+        } else {
+            style("ok").green()
+        };
+        println!(
+            "{:<config_width$}  {}  {:<host_width$}  {}  {}",
+            r.config,
+            style(format!("{:<id_width$}", r.id)).cyan(),
+            r.host,
+            running,
+            result
+        );
+    });
+
+    let summary = Summary::new(reports);
+    println!(
+        "total: {}, succeeded: {}, failed: {}",
+        style(summary.total).cyan(),
+        style(summary.succeeded).green(),
+        style(summary.failed).red()
+    );
+}
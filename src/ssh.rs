@@ -1,11 +1,21 @@
 use crate::Result;
-use anyhow::bail;
+use anyhow::{anyhow, bail};
 use bytes::Bytes;
 use ssh2::{FileStat, Session};
-use std::io::{Read, Write};
+use std::ffi::OsStr;
+use std::fmt;
+use std::io::{ErrorKind, Read, Write};
 use std::net::TcpStream;
 use std::path::{Path, PathBuf};
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+///会话中每次读写的默认超时
+const TIMEOUT: Duration = Duration::from_secs(30);
+///执行命令的通道的接收窗口, 先读取标准输出时标准错误暂存在窗口中,
+///默认的 2MB 窗口被标准错误占满后远程进程会阻塞在写标准错误上, 标准输出也就不会结束
+const EXEC_WINDOW: u32 = 256 * 1024 * 1024;
+///libssh2 默认的最大包大小
+const EXEC_PACKET: u32 = 32768;
 
 #[derive(Clone)]
 pub(crate) struct SSHClient {
@@ -13,31 +23,76 @@ pub(crate) struct SSHClient {
 }
 
 impl SSHClient {
-    pub(crate) fn new(tcp: TcpStream) -> Self {
-        let mut sess = Session::new().unwrap();
-        sess.set_timeout(Duration::from_secs(30).as_millis() as u32);
+    ///compress 为 true 时在握手时协商 zlib 压缩, 服务器不支持时使用不压缩的传输
+    pub(crate) fn new(tcp: TcpStream, compress: bool) -> Result<Self> {
+        let mut sess = Session::new()?;
+        sess.set_compress(compress);
+        sess.set_timeout(TIMEOUT.as_millis() as u32);
         sess.set_tcp_stream(tcp);
-        sess.handshake().unwrap();
-        Self { sess }
+        sess.handshake()?;
+        Ok(Self { sess })
     }
 
-    pub(crate) fn auth_by_password(&self, username: &str, password: &str) -> &Self {
-        self.sess.userauth_password(username, password).unwrap();
-        assert!(self.sess.authenticated());
-        self
+    pub(crate) fn auth_by_password(&self, username: &str, password: &str) -> Result<&Self> {
+        self.sess.userauth_password(username, password)?;
+        if !self.sess.authenticated() {
+            bail!("认证失败: {}", username);
+        }
+        Ok(self)
+    }
+    pub(crate) fn auth_by_private_key(
+        &self,
+        username: &str,
+        private_key: impl AsRef<Path>,
+        passphrase: Option<&str>,
+    ) -> Result<&Self> {
+        self.sess
+            .userauth_pubkey_file(username, None, private_key.as_ref(), passphrase)?;
+        if !self.sess.authenticated() {
+            bail!("认证失败: {}", username);
+        }
+        Ok(self)
+    }
+
+    ///执行命令, 退出码不为 0 或被信号终止时返回包含标准错误的错误
+    pub(crate) fn exec(&self, command: &ShellCommand) -> Result<ExecOutput> {
+        self.exec_until(command, None)
     }
-    pub(crate) fn exec(&self, command: &str) -> Result<String> {
-        let mut channel = self.sess.channel_session()?;
-        channel.exec(command)?;
-        let mut output = String::new();
-        channel.read_to_string(&mut output)?;
+    ///执行命令, 总耗时最多 timeout, 例如执行时间较长且没有输出的 hook,
+    ///会话的超时不变, 超过 TIMEOUT 没有输出时继续等待, 每次等待后释放会话,
+    ///因此超时最多延迟 TIMEOUT, 同一个会话上的其他命令也不会一直被阻塞
+    pub(crate) fn exec_with_timeout(
+        &self,
+        command: &ShellCommand,
+        timeout: Duration,
+    ) -> Result<ExecOutput> {
+        self.exec_until(command, Some(Instant::now() + timeout))
+            .map_err(|e| match e.downcast_ref::<std::io::Error>() {
+                Some(io) if io.kind() == ErrorKind::TimedOut => {
+                    anyhow!("执行命令超时: {} (超过 {:?})", command, timeout)
+                }
+                _ => e,
+            })
+    }
+    ///执行命令, deadline 为 None 时读取超过 TIMEOUT 没有输出就返回错误
+    fn exec_until(&self, command: &ShellCommand, deadline: Option<Instant>) -> Result<ExecOutput> {
+        let start = Instant::now();
+        let mut channel = self
+            .sess
+            .channel_open("session", EXEC_WINDOW, EXEC_PACKET, None)?;
+        channel.exec(command.as_str())?;
+        //读取标准输出时标准错误暂存在 EXEC_WINDOW 中, 不会阻塞远程进程
+        let stdout = read_until(&mut channel, deadline)?;
+        let stderr = read_until(&mut channel.stderr(), deadline)?;
         channel.wait_close()?;
-        let exit_status = channel.exit_status()?;
-        if exit_status != 0 {
-            bail!("执行命令失败: {}", command);
-            //return Err(anyhow!("执行命令失败: {}", command));
+        ExecOutput {
+            stdout,
+            stderr,
+            exit_code: channel.exit_status()?,
+            signal: channel.exit_signal()?.exit_signal,
+            duration: start.elapsed(),
         }
-        Ok(output)
+        .check(command)
     }
     pub(crate) fn dir_exists(&self, remote_path: impl AsRef<Path>) -> Result<bool> {
         let remote_path = remote_path.as_ref();
@@ -45,24 +100,12 @@ impl SSHClient {
         let sftp = self.sess.sftp()?;
         Ok(sftp.realpath(remote_path.as_ref()).is_ok())
     }
-    pub(crate) fn file_exists(&self, remote_path: impl AsRef<Path>) -> Option<FileStat> {
-        // let mut channel = self.sess.channel_session().unwrap();
-        // let command = format!("test -e {}", remote_path);
-        // channel.exec(&command).unwrap();
-        // let mut output = Vec::new();
-        // channel.read_to_end(&mut output).unwrap();
-        // channel.wait_close().unwrap();
-        // let exit_status = channel.exit_status().unwrap();
-        // exit_status == 0
-        // 开始sftp会话
-        let sftp = self.sess.sftp().unwrap();
-
-        // 检查特定文件是否存在
-        match sftp.stat(remote_path.as_ref()) {
-            Ok(metadata) => Some(metadata),
-            Err(_) => None,
-        }
+    ///文件的状态, 文件不存在时为 None, 无法打开 sftp 会话时返回错误
+    pub(crate) fn file_exists(&self, remote_path: impl AsRef<Path>) -> Result<Option<FileStat>> {
+        let sftp = self.sess.sftp()?;
+        Ok(sftp.stat(remote_path.as_ref()).ok())
     }
+    #[allow(dead_code)]
     pub(crate) fn read_dir(&self, path: impl AsRef<Path>) -> Result<Vec<(PathBuf, FileStat)>> {
         // 开始sftp会话
         let sftp = self.sess.sftp()?;
@@ -118,11 +161,204 @@ impl SSHClient {
     // }
 }
 
+///读取到结束, 会话超时后没有到 deadline 时继续读取
+fn read_until(reader: &mut impl Read, deadline: Option<Instant>) -> Result<String> {
+    let mut data = Vec::new();
+    let mut buf = [0; 8192];
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => data.extend_from_slice(&buf[..n]),
+            Err(e)
+                if e.kind() == ErrorKind::TimedOut
+                    && deadline.is_some_and(|d| Instant::now() < d) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(String::from_utf8_lossy(&data).into_owned())
+}
+
+///远程命令的执行结果
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ExecOutput {
+    pub(crate) stdout: String,
+    pub(crate) stderr: String,
+    pub(crate) exit_code: i32,
+    ///被信号终止时的信号名称, 例如 KILL
+    pub(crate) signal: Option<String>,
+    pub(crate) duration: Duration,
+}
+
+impl ExecOutput {
+    pub(crate) fn success(&self) -> bool {
+        self.exit_code == 0 && self.signal.is_none()
+    }
+
+    ///失败时返回包含命令, 退出码或信号, 耗时和标准错误的错误,
+    ///没有标准错误时使用标准输出, 例如用 2>&1 合并了输出的 hook
+    pub(crate) fn check(self, command: &ShellCommand) -> Result<Self> {
+        if !self.success() {
+            let output = match self.stderr.trim().is_empty() {
+                true => &self.stdout,
+                false => &self.stderr,
+            };
+            bail!(
+                "执行命令失败: {} ({}, 耗时 {:?})\n{}",
+                command,
+                match &self.signal {
+                    Some(signal) => format!("信号 {}", signal),
+                    None => format!("退出码 {}", self.exit_code),
+                },
+                self.duration,
+                output.trim_end()
+            );
+        }
+        Ok(self)
+    }
+}
+
+///在远程服务器上执行的 POSIX sh 命令, 参数都会加上单引号,
+///不加引号的 shell 语法只能是常量, 避免路径中的空格和配置中的特殊字符被 shell 解释
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct ShellCommand(String);
+
+impl ShellCommand {
+    pub(crate) fn new(program: impl AsRef<OsStr>) -> Self {
+        Self::default().arg(program)
+    }
+
+    pub(crate) fn arg(mut self, arg: impl AsRef<OsStr>) -> Self {
+        self.push(&quote(arg));
+        self
+    }
+
+    pub(crate) fn args<I>(self, args: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: AsRef<OsStr>,
+    {
+        args.into_iter().fold(self, Self::arg)
+    }
+
+    ///设置 shell 变量, 例如 f='/opt/my app';
+    pub(crate) fn var(mut self, name: &'static str, value: impl AsRef<OsStr>) -> Self {
+        self.push(&format!("{}={};", name, quote(value)));
+        self
+    }
+
+    ///不加引号的 shell 语法, 例如 &&, |, 2>&1, if [ -e
+    pub(crate) fn raw(mut self, syntax: &'static str) -> Self {
+        self.push(syntax);
+        self
+    }
+
+    pub(crate) fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    fn push(&mut self, s: &str) {
+        if !self.0.is_empty() {
+            self.0.push(' ');
+        }
+        self.0.push_str(s);
+    }
+}
+
+impl fmt::Display for ShellCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+///按 POSIX sh 的规则加上单引号, 只包含安全字符时原样返回
+pub(crate) fn quote(s: impl AsRef<OsStr>) -> String {
+    let s = s.as_ref().to_string_lossy();
+    let safe = |c: char| c.is_ascii_alphanumeric() || "_@%+=:,./-".contains(c);
+    if !s.is_empty() && s.chars().all(safe) {
+        return s.into_owned();
+    }
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
 #[cfg(test)]
 mod test {
-   
+
+    use crate::ssh::{quote, read_until, ExecOutput, ShellCommand};
+    use std::io::{self, Read};
+    use std::time::Duration;
+    use std::time::Instant;
+
     #[tokio::test]
-    async fn t() {
-       
+    async fn t() {}
+
+    #[test]
+    fn quote_arguments() {
+        assert_eq!(quote("/opt/app-1.0/app.log"), "/opt/app-1.0/app.log");
+        assert_eq!(quote(""), "''");
+        assert_eq!(quote("my app"), "'my app'");
+        assert_eq!(quote("a'; rm -rf /; '"), r"'a'\''; rm -rf /; '\'''");
+        let command = ShellCommand::default()
+            .raw("if [ -e")
+            .arg("/opt/my app")
+            .raw("]; then")
+            .args(["mv", "/opt/my app", "/opt/my app.bak"])
+            .raw("; fi");
+        assert_eq!(
+            command.as_str(),
+            "if [ -e '/opt/my app' ]; then mv '/opt/my app' '/opt/my app.bak' ; fi"
+        );
+        let output = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(ShellCommand::new("echo").arg("$HOME `id` ' \"").as_str())
+            .output()
+            .unwrap();
+        assert_eq!(output.stdout, b"$HOME `id` ' \"\n");
+    }
+
+    #[test]
+    fn exec_output_errors() {
+        let command = ShellCommand::new("ls").arg("/opt/my app");
+        let output = |exit_code, signal: Option<&str>| ExecOutput {
+            stdout: "out\n".to_owned(),
+            stderr: "ls: /opt/my app: No such file or directory\n".to_owned(),
+            exit_code,
+            signal: signal.map(str::to_owned),
+            duration: Duration::from_millis(5),
+        };
+        assert_eq!(output(0, None).check(&command).unwrap().stdout, "out\n");
+        assert_eq!(
+            output(2, None).check(&command).unwrap_err().to_string(),
+            "执行命令失败: ls '/opt/my app' (退出码 2, 耗时 5ms)\nls: /opt/my app: No such file or directory"
+        );
+        assert_eq!(
+            output(0, Some("KILL")).check(&command).unwrap_err().to_string(),
+            "执行命令失败: ls '/opt/my app' (信号 KILL, 耗时 5ms)\nls: /opt/my app: No such file or directory"
+        );
+    }
+
+    #[test]
+    fn read_until_deadline() {
+        //前两次读取时会话超时, 然后读到输出
+        struct Slow(Vec<io::Result<&'static [u8]>>);
+        impl Read for Slow {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                match self.0.pop() {
+                    None => Ok(0),
+                    Some(Ok(data)) => {
+                        buf[..data.len()].copy_from_slice(data);
+                        Ok(data.len())
+                    }
+                    Some(Err(e)) => Err(e),
+                }
+            }
+        }
+        let slow = || {
+            let timeout = || Err(io::Error::from(io::ErrorKind::TimedOut));
+            Slow(vec![Ok(b"done\n"), timeout(), timeout()])
+        };
+        let later = Instant::now() + Duration::from_secs(60);
+        assert_eq!(read_until(&mut slow(), Some(later)).unwrap(), "done\n");
+        assert!(read_until(&mut slow(), None).is_err());
+        assert!(read_until(&mut slow(), Some(Instant::now())).is_err());
     }
 }
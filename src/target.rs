@@ -0,0 +1,420 @@
+use crate::config::{self, Config, ConfigFormat, LoadOptions, SSHAccount};
+use crate::executable::Executable;
+use crate::ssh::SSHClient;
+use crate::Result;
+use anyhow::{anyhow, bail, Context};
+use glob::Pattern;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::fs;
+
+///一个配置文件中的一个可执行文件
+#[derive(Clone)]
+pub(crate) struct Target {
+    pub(crate) config: String,
+    pub(crate) id: String,
+    ///主机名称, 使用 ssh_account 时为地址
+    pub(crate) host: String,
+    ///主机所在的主机组
+    pub(crate) groups: Vec<String>,
+    ///渲染模板使用的变量
+    vars: BTreeMap<String, String>,
+    ///加载配置失败时保存错误信息
+    setting: std::result::Result<(SSHAccount, config::Executable), String>,
+}
+
+impl Target {
+    ///测试使用的目标, 主机名称为账号的地址
+    #[cfg(test)]
+    pub(crate) fn new(config: &str, ssh_account: SSHAccount, e: config::Executable) -> Self {
+        Self {
+            config: config.to_owned(),
+            id: e.id.clone(),
+            host: ssh_account.addr.clone(),
+            groups: Vec::new(),
+            vars: BTreeMap::new(),
+            setting: Ok((ssh_account, e)),
+        }
+    }
+
+    ///可执行文件的配置
+    pub(crate) fn executable_config(&self) -> Result<&config::Executable> {
+        let (_, e) = self.setting.as_ref().map_err(|e| anyhow!("{}", e))?;
+        Ok(e)
+    }
+
+    ///丢弃连接池中目标的会话, 包括连接失败的结果, 下次 connect 时重新连接
+    pub(crate) fn disconnect(&self, pool: &SessionPool) {
+        if let Ok((ssh_account, _)) = &self.setting {
+            pool.remove(ssh_account);
+        }
+    }
+
+    ///从连接池获取会话并创建 Executable
+    pub(crate) fn connect(&self, pool: &SessionPool) -> Result<Executable> {
+        let (ssh_account, e) = self.setting.as_ref().map_err(|e| anyhow!("{}", e))?;
+        if e.local_path.as_os_str().is_empty() {
+            bail!("{} 的 local_path 不能为空", e.id);
+        }
+        if e.executable_file_name.is_empty() || e.remote_path.as_os_str().is_empty() {
+            bail!("{} 的 executable_file_name 和 remote_path 不能为空", e.id);
+        }
+        let mut files = config::expand_files(&e.files, &e.remote_path)?;
+        files.extend(config::render_templates(
+            &e.templates,
+            &e.remote_path,
+            &self.vars,
+        )?);
+        let ssh_client = pool.get(ssh_account)?;
+        Ok(Executable::new(
+            ssh_client,
+            e.executable_file_name.clone(),
+            &e.remote_path,
+            &e.local_path,
+        )?
+        .with_args(e.args.clone(), e.env.clone())
+        .with_files(files)
+        .with_transfer(e.transfer.clone()))
+    }
+}
+
+type Session = Arc<OnceLock<std::result::Result<Arc<SSHClient>, String>>>;
+
+///会话池, 每个主机的每个用户只建立一个会话, 连接失败的结果同样保留, 避免重复连接不可用的主机
+#[derive(Default)]
+pub(crate) struct SessionPool {
+    sessions: Mutex<HashMap<(String, String), Session>>,
+}
+
+impl SessionPool {
+    pub(crate) fn get(&self, ssh_account: &SSHAccount) -> Result<Arc<SSHClient>> {
+        let session = self
+            .sessions
+            .lock()
+            .unwrap()
+            .entry((ssh_account.addr.clone(), ssh_account.username.clone()))
+            .or_default()
+            .clone();
+        //同一账号的其他任务会等待这里的连接完成
+        session
+            .get_or_init(|| {
+                get_ssh_client(ssh_account)
+                    .map(Arc::new)
+                    .map_err(|e| format!("{:#}", e))
+            })
+            .clone()
+            .map_err(|e| anyhow!(e))
+    }
+
+    ///删除会话, 正在使用的任务不受影响
+    pub(crate) fn remove(&self, ssh_account: &SSHAccount) {
+        self.sessions
+            .lock()
+            .unwrap()
+            .remove(&(ssh_account.addr.clone(), ssh_account.username.clone()));
+    }
+}
+
+pub(crate) fn get_ssh_client(ssh_account: &SSHAccount) -> Result<SSHClient> {
+    let host = ssh_account.addr.as_str();
+    let tcp = TcpStream::connect(host).with_context(|| format!("连接 {} 失败", host))?;
+    let client = SSHClient::new(tcp, ssh_account.compress)
+        .with_context(|| format!("与 {} 握手失败", host))?;
+    match &ssh_account.private_key {
+        Some(private_key) => {
+            let passphrase = Some(ssh_account.password.as_str()).filter(|p| !p.is_empty());
+            client.auth_by_private_key(&ssh_account.username, private_key, passphrase)?
+        }
+        None => client.auth_by_password(&ssh_account.username, &ssh_account.password)?,
+    };
+    Ok(client)
+}
+
+fn get_executable_dir() -> Result<PathBuf> {
+    Ok(env::current_dir()?)
+}
+
+fn get_config_name(config_path: &Path) -> String {
+    config_path
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned()
+}
+
+///每个可执行文件在每个主机上对应一个目标
+fn get_targets(config_name: &str, config: Config) -> Vec<Target> {
+    let mut targets = Vec::new();
+    for e in &config.executable_config_list {
+        match config.resolve_hosts(e) {
+            Ok(hosts) => targets.extend(hosts.into_iter().map(|(host, ssh_account)| Target {
+                config: config_name.to_owned(),
+                id: e.id.clone(),
+                groups: config.host_groups(&host),
+                vars: config.template_vars(e, &host, &ssh_account),
+                host,
+                setting: Ok((ssh_account, e.clone())),
+            })),
+            Err(err) => targets.push(Target {
+                config: config_name.to_owned(),
+                id: e.id.clone(),
+                host: String::new(),
+                groups: Vec::new(),
+                vars: BTreeMap::new(),
+                setting: Err(format!("{:#}", err)),
+            }),
+        }
+    }
+    targets
+}
+
+///按 id 和标签选择目标, id 和 exclude 可以使用通配符, 参数为空时不过滤,
+///配置加载失败或主机解析失败的目标总是保留, 错误会出现在结果中
+pub(crate) fn select_targets(
+    targets: Vec<Target>,
+    ids: &[String],
+    tags: &[String],
+    exclude: &[String],
+) -> Result<Vec<Target>> {
+    let patterns = |patterns: &[String]| {
+        patterns
+            .iter()
+            .map(|p| Pattern::new(p).with_context(|| format!("无效的匹配模式: {}", p)))
+            .collect::<Result<Vec<_>>>()
+    };
+    let (ids, exclude) = (patterns(ids)?, patterns(exclude)?);
+    Ok(targets
+        .into_iter()
+        .filter(|t| {
+            if t.setting.is_err() {
+                return true;
+            }
+            let target_tags = t
+                .executable_config()
+                .map(|e| e.tags.as_slice())
+                .unwrap_or_default();
+            (ids.is_empty() || ids.iter().any(|p| p.matches(&t.id)))
+                && (tags.is_empty() || target_tags.iter().any(|tag| tags.contains(tag)))
+                && !exclude
+                    .iter()
+                    .any(|p| p.matches(&t.id) || target_tags.iter().any(|tag| p.matches(tag)))
+        })
+        .collect())
+}
+
+///按主机和主机组过滤, 参数为空时不过滤, 和 select_targets 一样保留出错的目标
+pub(crate) fn filter_targets(
+    targets: Vec<Target>,
+    hosts: &[String],
+    groups: &[String],
+) -> Vec<Target> {
+    targets
+        .into_iter()
+        .filter(|t| {
+            t.setting.is_err()
+                || hosts.is_empty()
+                || hosts.contains(&t.host)
+                || t.setting
+                    .as_ref()
+                    .is_ok_and(|(ssh_account, _)| hosts.contains(&ssh_account.addr))
+        })
+        .filter(|t| {
+            t.setting.is_err() || groups.is_empty() || t.groups.iter().any(|g| groups.contains(g))
+        })
+        .collect()
+}
+
+///没有指定配置文件时使用当前目录下的 exe-default-config.yaml
+pub(crate) fn get_config_path(config: Option<Box<Path>>) -> Result<Box<Path>> {
+    let mut default_config = get_executable_dir()?;
+    default_config.push("exe-default-config.yaml");
+    Ok(config.unwrap_or(Box::from(default_config.as_path())))
+}
+
+///目录中所有 exe-*.yaml, exe-*.yml, exe-*.toml 和 exe-*.json 配置文件
+pub(crate) async fn get_config_files(config_dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut config_files = Vec::new();
+
+    let mut dir = fs::read_dir(config_dir).await?;
+    while let Some(d) = dir.next_entry().await? {
+        let path = d.path();
+        if path.is_file() {
+            let filename = path.file_name().unwrap().to_string_lossy();
+            let extension = path.extension().unwrap_or_default().to_string_lossy();
+            if filename.starts_with("exe-")
+                && ConfigFormat::EXTENSIONS.contains(&extension.as_ref())
+            {
+                config_files.push(path);
+            }
+        }
+    }
+    config_files.sort();
+    Ok(config_files)
+}
+
+pub(crate) async fn get_all_executable(
+    config: Option<Box<Path>>,
+    options: &LoadOptions,
+) -> Result<Vec<Target>> {
+    let config_path = get_config_path(config)?;
+    let config_name = get_config_name(config_path.as_ref());
+
+    let config = Config::load(config_path.as_ref(), options).await?;
+    Ok(get_targets(&config_name, config))
+}
+
+pub(crate) async fn get_all_config_executable(
+    config_dir: Box<Path>,
+    options: &LoadOptions,
+) -> Result<Vec<Target>> {
+    let config_files = get_config_files(config_dir.as_ref()).await?;
+
+    let mut executable_list = Vec::new();
+
+    for config_file in config_files {
+        let config_name = get_config_name(config_file.as_path());
+        match Config::load(config_file.as_path(), options).await {
+            Ok(config) => executable_list.extend(get_targets(&config_name, config)),
+            Err(e) => {
+                //配置加载失败时记录一个没有 id 的目标, 继续处理其他配置
+                executable_list.push(Target {
+                    config: config_name,
+                    id: String::new(),
+                    host: String::new(),
+                    groups: Vec::new(),
+                    vars: BTreeMap::new(),
+                    setting: Err(format!("加载配置失败: {:#}", e)),
+                });
+            }
+        }
+    }
+
+    Ok(executable_list)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::Config;
+    use crate::config::SSHAccount;
+    use crate::target::{filter_targets, get_targets, select_targets, SessionPool, Target};
+    use std::collections::BTreeMap;
+
+    fn target(id: &str, tags: &str) -> Target {
+        let executable = format!(
+            "{{id: {}, executable_file_name: x, local_path: ., remote_path: /x/, tags: [{}]}}",
+            id, tags
+        );
+        let ssh_account = SSHAccount {
+            addr: "h:22".to_owned(),
+            username: "root".to_owned(),
+            password: String::new(),
+            private_key: None,
+            vars: BTreeMap::new(),
+            compress: false,
+        };
+        Target {
+            config: "c".to_owned(),
+            id: id.to_owned(),
+            host: "h:22".to_owned(),
+            groups: Vec::new(),
+            vars: BTreeMap::new(),
+            setting: Ok((ssh_account, serde_yaml::from_str(&executable).unwrap())),
+        }
+    }
+
+    #[test]
+    fn select_by_id_tag_and_exclude() {
+        let mut broken = target("", "");
+        broken.setting = Err("加载配置失败".to_owned());
+        let targets = vec![
+            target("api-user", "backend"),
+            target("api-order", "backend, canary"),
+            target("web", "frontend"),
+            broken,
+        ];
+        let select = |ids: &[&str], tags: &[&str], exclude: &[&str]| {
+            let strings = |s: &[&str]| s.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+            select_targets(
+                targets.clone(),
+                &strings(ids),
+                &strings(tags),
+                &strings(exclude),
+            )
+            .unwrap()
+            .into_iter()
+            .map(|t| t.id)
+            .collect::<Vec<_>>()
+        };
+        //加载失败的配置总是保留, 错误会出现在结果中
+        assert_eq!(select(&["api-*"], &[], &[]), ["api-user", "api-order", ""]);
+        assert_eq!(
+            select(&["web", "api-user"], &[], &[]),
+            ["api-user", "web", ""]
+        );
+        assert_eq!(select(&[], &["backend"], &["canary"]), ["api-user", ""]);
+        assert_eq!(select(&[], &[], &["api-*"]), ["web", ""]);
+        assert!(select_targets(targets.clone(), &["[".to_owned()], &[], &[]).is_err());
+    }
+
+    #[test]
+    fn resolve_and_filter_hosts() {
+        let config: Config = serde_yaml::from_str(
+            "
+hosts:
+  a: {addr: 'a:22', username: root}
+  b: {addr: 'b:22', username: root}
+  c: {addr: 'c:22', username: root}
+groups:
+  web: [a, b]
+  db: [c]
+executable_config_list:
+  - {id: web, executable_file_name: x, local_path: ., remote_path: /x, groups: [web], hosts: [a]}
+  - {id: db, executable_file_name: x, local_path: ., remote_path: /x, hosts: [c]}
+  - {id: none, executable_file_name: x, local_path: ., remote_path: /x}
+",
+        )
+        .unwrap();
+        let targets = get_targets("c", config);
+        let hosts = |targets: &[Target]| {
+            targets
+                .iter()
+                .map(|t| format!("{}@{}", t.id, t.host))
+                .collect::<Vec<_>>()
+        };
+        //组和主机合并去重, 没有指定主机时报错而不是部署到所有主机
+        assert_eq!(hosts(&targets), ["web@a", "web@b", "db@c", "none@"]);
+        assert!(targets[3].setting.is_err());
+        assert_eq!(targets[0].groups, ["web"]);
+
+        let filter = |hosts: &[&str], groups: &[&str]| {
+            let strings = |s: &[&str]| s.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+            filter_targets(targets.clone(), &strings(hosts), &strings(groups))
+        };
+        assert_eq!(hosts(&filter(&["b"], &[])), ["web@b", "none@"]);
+        assert_eq!(hosts(&filter(&["c:22"], &[])), ["db@c", "none@"]);
+        assert_eq!(hosts(&filter(&[], &["db"])), ["db@c", "none@"]);
+        assert_eq!(hosts(&filter(&["a", "c"], &["web"])), ["web@a", "none@"]);
+    }
+
+    #[test]
+    fn remove_failed_session() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        let ssh_account: SSHAccount =
+            serde_yaml::from_str(&format!("{{addr: '{}', username: root}}", addr)).unwrap();
+        let error = |pool: &SessionPool| format!("{:#}", pool.get(&ssh_account).err().unwrap());
+        let pool = SessionPool::default();
+        assert!(error(&pool).contains("连接"));
+
+        //主机恢复后, 连接失败的结果保留到删除会话为止
+        let listener = std::net::TcpListener::bind(&addr).unwrap();
+        std::thread::spawn(move || listener.incoming().flatten().for_each(drop));
+        assert!(error(&pool).contains("连接"));
+        pool.remove(&ssh_account);
+        assert!(error(&pool).contains("握手"));
+    }
+}
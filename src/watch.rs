@@ -0,0 +1,190 @@
+use crate::build::build;
+use crate::cli::Watch;
+use crate::deploy::{deploy, run_before_upload};
+use crate::executable::Executable;
+use crate::report::print_errors;
+use crate::target::{SessionPool, Target};
+use crate::Result;
+use console::style;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+///文件的修改时间和大小, 文件不存在时为 None
+type Snapshot = Vec<(PathBuf, Option<(SystemTime, u64)>)>;
+
+///一个目标的日志读取状态
+#[derive(Default)]
+struct LogState {
+    ///读取日志使用的连接, 出错后重新连接
+    executable: Option<Executable>,
+    ///已经输出的日志大小, None 时从日志末尾开始
+    offset: Option<u64>,
+    ///上一次的错误, 相同的错误只输出一次
+    error: Option<String>,
+}
+
+///监视本地可执行文件, 变化后重新部署并输出日志, --build 时源码变化后先编译
+pub(crate) fn watch(targets: Vec<Target>, watch: &Watch) -> Result<()> {
+    let interval = Duration::from_millis(watch.interval_ms);
+    let debounce = Duration::from_millis(watch.debounce_ms);
+    let pool = SessionPool::default();
+
+    let mut binaries: Vec<PathBuf> = targets
+        .iter()
+        .filter_map(|t| t.executable_config().ok())
+        .map(|e| e.local_path.join(&e.executable_file_name))
+        .collect();
+    binaries.sort();
+    binaries.dedup();
+    let sources = match watch.build {
+        true => watch.source.clone(),
+        false => Vec::new(),
+    };
+    binaries
+        .iter()
+        .chain(&sources)
+        .for_each(|p| eprintln!("{} {}", style("watching").cyan(), p.display()));
+
+    let mut binary_snapshot = snapshot(&binaries);
+    let mut source_snapshot = snapshot(&sources);
+    let mut logs: Vec<LogState> = targets.iter().map(|_| LogState::default()).collect();
+    loop {
+        if watch.build && wait_for_change(&sources, &mut source_snapshot, debounce) {
+            eprintln!("{} source changed", style("build").cyan());
+            if let Err(e) = build(&targets) {
+                eprintln!("{} {:#}", style("error").red(), e);
+            }
+        }
+        if wait_for_change(&binaries, &mut binary_snapshot, debounce) {
+            let before_upload = run_before_upload(&targets);
+            for (target, log) in targets.iter().zip(logs.iter_mut()) {
+                let name = format!("{}@{}", target.id, target.host);
+                eprintln!("{} {}", style("deploy").cyan(), name);
+                let report = deploy(target, &pool, &before_upload);
+                print_errors(&report);
+                match report.is_failed() {
+                    //连接失败或断开后下次重新连接
+                    true => {
+                        eprintln!("{} {}", name, style("failed").red());
+                        target.disconnect(&pool);
+                        log.executable = None;
+                    }
+                    false => eprintln!("{} {}", name, style("ok").green()),
+                }
+                //重新启动后日志被清空
+                log.offset = Some(0);
+            }
+        }
+        for (target, log) in targets.iter().zip(logs.iter_mut()) {
+            stream_log(target, &pool, log, targets.len() > 1);
+        }
+        thread::sleep(interval);
+    }
+}
+
+///输出新增的日志, 只在第一次读取时连接, 出错时输出错误并在下次重新连接
+fn stream_log(target: &Target, pool: &SessionPool, log: &mut LogState, prefix: bool) {
+    let result = match log.executable.take() {
+        Some(executable) => Ok(executable),
+        None => target.connect(pool),
+    }
+    .and_then(|executable| {
+        let result = match log.offset {
+            Some(offset) => executable.read_remote_server_process_log_from(offset),
+            //先读取一次得到日志的大小, 不输出已有的日志
+            None => executable.log_size().map(|size| (size, String::new())),
+        };
+        log.executable = Some(executable);
+        result
+    });
+    match result {
+        Ok((new_offset, lines)) => {
+            log.offset = Some(new_offset);
+            log.error = None;
+            lines.lines().for_each(|line| match prefix {
+                true => println!("{} {}", style(format!("[{}]", target.id)).cyan(), line),
+                false => println!("{}", line),
+            });
+        }
+        Err(e) => {
+            let error = format!("{:#}", e);
+            if log.error.as_ref() != Some(&error) {
+                eprintln!(
+                    "{} {}@{}: {}",
+                    style("log").red(),
+                    target.id,
+                    target.host,
+                    error
+                );
+            }
+            log.error = Some(error);
+            target.disconnect(pool);
+            log.executable = None;
+        }
+    }
+}
+
+///检查文件是否变化, 变化后等待 debounce 时间内不再变化再返回 true
+fn wait_for_change(paths: &[PathBuf], last: &mut Snapshot, debounce: Duration) -> bool {
+    let mut current = snapshot(paths);
+    if current == *last {
+        return false;
+    }
+    loop {
+        thread::sleep(debounce);
+        let next = snapshot(paths);
+        if next == current {
+            break;
+        }
+        current = next;
+    }
+    *last = current;
+    true
+}
+
+///文件和目录中所有文件的修改时间和大小
+fn snapshot(paths: &[PathBuf]) -> Snapshot {
+    let mut snapshot = Vec::new();
+    paths.iter().for_each(|p| collect(p, &mut snapshot));
+    snapshot
+}
+
+fn collect(path: &Path, snapshot: &mut Snapshot) {
+    if path.is_dir() {
+        let mut entries: Vec<_> = std::fs::read_dir(path)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|e| e.path())
+            .collect();
+        entries.sort();
+        entries.iter().for_each(|p| collect(p, snapshot));
+        return;
+    }
+    let state = path
+        .metadata()
+        .ok()
+        .and_then(|m| Some((m.modified().ok()?, m.len())));
+    snapshot.push((path.to_path_buf(), state));
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test_util::TempDir;
+    use crate::watch::{snapshot, wait_for_change};
+    use std::time::Duration;
+
+    #[test]
+    fn detect_change_in_dir() {
+        let dir = TempDir::new("watch");
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        std::fs::write(dir.join("src/main.rs"), "fn main() {}").unwrap();
+        let paths = [dir.to_path_buf()];
+        let mut last = snapshot(&paths);
+        assert!(!wait_for_change(&paths, &mut last, Duration::ZERO));
+        std::fs::write(dir.join("src/lib.rs"), "").unwrap();
+        assert!(wait_for_change(&paths, &mut last, Duration::ZERO));
+        assert!(!wait_for_change(&paths, &mut last, Duration::ZERO));
+    }
+}